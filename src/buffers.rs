@@ -0,0 +1,86 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use parking_lot::Mutex;
+
+use serde_json::Value;
+
+/// Buffers grown past this by a large response, e.g. a big `queue_dump`, aren't kept
+const MAX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Once a buffer has less room than this left, it makes room for many more typical responses
+const MIN_ROOM: usize = 512;
+const RESERVED_SIZE: usize = 4096;
+
+/// Buffers that responses are serialized into (`--response-buffers`). A kept buffer already
+/// has room for a typical response, so serializing doesn't grow a new one a few bytes at a time.
+/// The body is split off the buffer rather than copied, and once hyper has sent and dropped
+/// it, the buffer takes its memory back.
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    max: usize,
+}
+
+impl BufferPool {
+    pub fn new(max: usize) -> BufferPool {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max)),
+            max,
+        }
+    }
+
+    /// Serializes `value` like `to_string`
+    pub fn serialize(&self, value: &Value) -> Bytes {
+        if self.max == 0 {
+            return Bytes::from(serde_json::to_vec(value).expect("Failed to serialize response"));
+        }
+        let mut buffer = self.buffers.lock().pop().unwrap_or_default();
+        if buffer.capacity() < MIN_ROOM {
+            // Takes the memory back if every body split off it was dropped, or else allocates anew
+            buffer.reserve(RESERVED_SIZE);
+        }
+        serde_json::to_writer((&mut buffer).writer(), value).expect("Failed to serialize response");
+        let body = buffer.split().freeze();
+        if body.len() + buffer.capacity() <= MAX_BUFFER_SIZE {
+            let mut buffers = self.buffers.lock();
+            if buffers.len() < self.max {
+                buffers.push(buffer);
+            }
+        }
+        body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn buffers_come_back_to_the_pool() {
+        let pool = BufferPool::new(1);
+        let value = json!({ "work": "2bf29ef00786a6bc", "difficulty": "fffffff800000000" });
+        let first = pool.serialize(&value);
+        assert_eq!(first, serde_json::to_vec(&value).unwrap());
+        let start = first.as_ptr();
+        // The body was split off the front of the buffer, which keeps the rest of its memory
+        let next = pool.buffers.lock()[0].as_ptr();
+        assert_eq!(next, start.wrapping_add(first.len()));
+        let second = pool.serialize(&value);
+        assert_eq!(second.as_ptr(), next);
+        drop((first, second));
+        while pool.buffers.lock()[0].capacity() >= MIN_ROOM {
+            pool.serialize(&value);
+        }
+        // Every body was dropped, so the buffer takes its memory back rather than allocating
+        assert_eq!(pool.serialize(&value).as_ptr(), start);
+    }
+
+    #[test]
+    fn large_buffers_are_not_kept() {
+        let pool = BufferPool::new(1);
+        let body = pool.serialize(&json!("x".repeat(MAX_BUFFER_SIZE)));
+        assert_eq!(body.len(), MAX_BUFFER_SIZE + 2);
+        assert!(pool.buffers.lock().is_empty());
+    }
+}
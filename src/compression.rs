@@ -0,0 +1,134 @@
+use std::io::{self, Read, Write};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+/// Responses smaller than this aren't worth compressing
+pub const MIN_SIZE: usize = 1024;
+
+/// Request bodies decompressing to more than this are rejected, so a small zip bomb can't
+/// exhaust memory
+pub const MAX_DECOMPRESSED_SIZE: u64 = 1 << 20;
+
+/// Why a request body couldn't be decompressed
+pub enum DecompressError {
+    TooLarge,
+    Invalid(io::Error),
+}
+
+#[derive(Clone, Copy)]
+pub enum Encoding {
+    Gzip,
+    /// HTTP's "deflate" is zlib wrapped deflate
+    Deflate,
+}
+
+impl Encoding {
+    /// Parses a `Content-Encoding` header. `Ok(None)` is the identity encoding.
+    pub fn from_header(value: &str) -> Result<Option<Encoding>, ()> {
+        match value.trim() {
+            name if name.eq_ignore_ascii_case("gzip") => Ok(Some(Encoding::Gzip)),
+            name if name.eq_ignore_ascii_case("deflate") => Ok(Some(Encoding::Deflate)),
+            name if name.is_empty() || name.eq_ignore_ascii_case("identity") => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(data).expect("Failed to compress");
+                encoder.finish().expect("Failed to compress")
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(data).expect("Failed to compress");
+                encoder.finish().expect("Failed to compress")
+            }
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>, DecompressError> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Encoding::Gzip => Box::new(GzDecoder::new(data)),
+            Encoding::Deflate => Box::new(ZlibDecoder::new(data)),
+        };
+        let mut out = Vec::new();
+        decoder
+            .take(MAX_DECOMPRESSED_SIZE + 1)
+            .read_to_end(&mut out)
+            .map_err(DecompressError::Invalid)?;
+        if out.len() as u64 > MAX_DECOMPRESSED_SIZE {
+            return Err(DecompressError::TooLarge);
+        }
+        Ok(out)
+    }
+}
+
+/// Picks an encoding from an `Accept-Encoding` header, preferring gzip
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let accepted = |name: &str| {
+        accept_encoding.split(',').any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            parts.next().is_some_and(|n| n.eq_ignore_ascii_case(name))
+                && !parts.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                })
+        })
+    };
+    if accepted("gzip") {
+        Some(Encoding::Gzip)
+    } else if accepted("deflate") {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let body = br#"{"action": "work_generate", "hash": "718cc2121c3e641059bc1c2cfc45666c99e8ae922f7a807b7d07b62c995d79e2"}"#;
+        for encoding in [Encoding::Gzip, Encoding::Deflate] {
+            assert!(matches!(encoding.decompress(&encoding.compress(body)), Ok(out) if out == body));
+        }
+    }
+
+    #[test]
+    fn rejects_a_zip_bomb() {
+        let size = MAX_DECOMPRESSED_SIZE as usize;
+        for encoding in [Encoding::Gzip, Encoding::Deflate] {
+            let bomb = encoding.compress(&vec![b' '; size + 1]);
+            assert!(bomb.len() < 16 * 1024);
+            assert!(matches!(encoding.decompress(&bomb), Err(DecompressError::TooLarge)));
+            let largest = encoding.compress(&vec![b' '; size]);
+            assert!(matches!(encoding.decompress(&largest), Ok(out) if out.len() == size));
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_input() {
+        let body = vec![b'7'; 4096];
+        for encoding in [Encoding::Gzip, Encoding::Deflate] {
+            let compressed = encoding.compress(&body);
+            let truncated = &compressed[..compressed.len() / 2];
+            assert!(matches!(encoding.decompress(truncated), Err(DecompressError::Invalid(_))));
+            assert!(matches!(encoding.decompress(b"not compressed"), Err(DecompressError::Invalid(_))));
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::stream::{self, Stream};
+
+use log::error;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, Sleep};
+
+/// Bookkeeping shared between a connection and the requests served on it
+#[derive(Default)]
+pub struct ConnectionState {
+    in_flight: AtomicUsize,
+}
+
+impl ConnectionState {
+    fn in_flight(&self) -> usize {
+        self.in_flight.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// Marks a request as in flight on its connection until dropped
+pub struct RequestGuard {
+    state: Arc<ConnectionState>,
+}
+
+impl RequestGuard {
+    pub fn new(state: Arc<ConnectionState>) -> Self {
+        state.in_flight.fetch_add(1, atomic::Ordering::Relaxed);
+        RequestGuard { state }
+    }
+
+    /// The requests in flight on the connection, including this one
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight()
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.state.in_flight.fetch_sub(1, atomic::Ordering::Relaxed);
+    }
+}
+
+/// Counts the open connections, and holds the connection's slot under `--max-connections`
+struct ConnectionGuard {
+    connections: Arc<AtomicUsize>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionGuard {
+    fn new(connections: Arc<AtomicUsize>, permit: Option<OwnedSemaphorePermit>) -> Self {
+        connections.fetch_add(1, atomic::Ordering::Relaxed);
+        ConnectionGuard {
+            connections,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, atomic::Ordering::Relaxed);
+    }
+}
+
+/// An accepted TCP connection. If it has an idle timeout, it is closed once it
+/// has gone that long without any traffic while no request is in flight.
+/// If it has a write timeout, it is closed once a write has been stuck that long,
+/// i.e. the client stopped reading.
+pub struct Connection {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    state: Arc<ConnectionState>,
+    idle_timeout: Option<Duration>,
+    idle_sleep: Option<Pin<Box<Sleep>>>,
+    write_timeout: Option<Duration>,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+    _guard: ConnectionGuard,
+}
+
+impl Connection {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn state(&self) -> Arc<ConnectionState> {
+        self.state.clone()
+    }
+
+    fn reset_idle(&mut self) {
+        if let (Some(timeout), Some(sleep)) = (self.idle_timeout, &mut self.idle_sleep) {
+            sleep.as_mut().reset(Instant::now() + timeout);
+        }
+    }
+
+    fn poll_idle(&mut self, cx: &mut Context) -> io::Result<()> {
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+        if self.state.in_flight() > 0 {
+            // Waiting on work generation doesn't count as idle
+            return Ok(());
+        }
+        let sleep = self
+            .idle_sleep
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Connection idle for too long",
+            )),
+            Poll::Pending => Ok(()),
+        }
+    }
+
+    /// Tracks how long writes have been pending, after a write or flush returned `result`
+    fn poll_write_stall<T>(&mut self, cx: &mut Context, result: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let timeout = match (self.write_timeout, &result) {
+            (Some(timeout), Poll::Pending) => timeout,
+            _ => {
+                self.write_sleep = None;
+                return result;
+            }
+        };
+        let sleep = self
+            .write_sleep
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Client stopped reading the response",
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.stream).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() != filled {
+                    this.reset_idle();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => match this.poll_idle(cx) {
+                Ok(()) => Poll::Pending,
+                Err(err) => Poll::Ready(Err(err)),
+            },
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = result {
+            this.reset_idle();
+        }
+        this.poll_write_stall(cx, result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_flush(cx);
+        this.poll_write_stall(cx, result)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Accepts connections from `listener`. Under a connection limit, no further
+/// connection is accepted until a slot frees up, leaving them in the backlog.
+pub fn incoming(
+    listener: TcpListener,
+    limit: Option<Arc<Semaphore>>,
+    connections: Arc<AtomicUsize>,
+    idle_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> impl Stream<Item = io::Result<Connection>> {
+    stream::unfold(listener, move |listener| {
+        let limit = limit.clone();
+        let connections = connections.clone();
+        async move {
+            let permit = match limit {
+                Some(limit) => Some(
+                    limit
+                        .acquire_owned()
+                        .await
+                        .expect("Connection limit semaphore closed"),
+                ),
+                None => None,
+            };
+            let connection = loop {
+                match listener.accept().await {
+                    Ok((stream, remote_addr)) => {
+                        break Connection {
+                            stream,
+                            remote_addr,
+                            state: Arc::new(ConnectionState::default()),
+                            idle_timeout,
+                            idle_sleep: None,
+                            write_timeout,
+                            write_sleep: None,
+                            _guard: ConnectionGuard::new(connections, permit),
+                        }
+                    }
+                    Err(err) => {
+                        // Most likely out of file descriptors, give other connections time to close
+                        error!("Failed to accept connection: {}", err);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            };
+            Some((Ok(connection), listener))
+        }
+    })
+}
@@ -0,0 +1,71 @@
+/// The Vite base threshold, for a difficulty of 67108863
+pub const BASE_THRESHOLD: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xc0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0,
+];
+
+/// Thresholds up to this one are met by at least 255 of every 256 nonces
+pub const TRIVIAL_THRESHOLD: [u8; 32] = [
+    0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0,
+];
+
+/// Returns `2^256 - threshold`, which is proportional to how likely a random
+/// nonce is to meet the threshold.
+fn inverse(threshold: [u8; 32]) -> f64 {
+    if threshold == [0u8; 32] {
+        return 2f64.powi(256);
+    }
+    negate(threshold)
+        .iter()
+        .fold(0f64, |acc, &byte| acc * 256f64 + byte as f64)
+}
+
+/// Two's complement negation, modulo 2^256
+fn negate(mut value: [u8; 32]) -> [u8; 32] {
+    let mut carry = true;
+    for byte in value.iter_mut().rev() {
+        *byte = !*byte;
+        if carry {
+            *byte = byte.wrapping_add(1);
+            carry = *byte == 0;
+        }
+    }
+    value
+}
+
+/// Converts a float in `[0, 2^256)` to a 256 bit big endian integer
+fn float_to_bytes(value: f64) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    if value < 1f64 {
+        return out;
+    }
+    let bits = value.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i64 - 1075;
+    let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+    for i in 0..53 {
+        let bit = i + exponent;
+        if mantissa & (1 << i) != 0 && (0..256).contains(&bit) {
+            out[31 - (bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+    out
+}
+
+/// How many times harder `threshold` is to reach than `base`
+pub fn to_multiplier(threshold: [u8; 32], base: [u8; 32]) -> f64 {
+    inverse(base) / inverse(threshold)
+}
+
+/// The threshold that is `multiplier` times harder to reach than `base`
+pub fn from_multiplier(multiplier: f64, base: [u8; 32]) -> [u8; 32] {
+    let inverse = inverse(base) / multiplier;
+    if inverse >= 2f64.powi(256) {
+        return [0u8; 32];
+    }
+    let inverse = float_to_bytes(inverse);
+    if inverse == [0u8; 32] {
+        return [0xffu8; 32];
+    }
+    negate(inverse)
+}
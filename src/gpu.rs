@@ -1,20 +1,133 @@
-use ocl;
 use ocl::builders::DeviceSpecifier;
 use ocl::builders::ProgramBuilder;
+use ocl::enums::{DeviceInfo, DeviceInfoResult};
 use ocl::flags::MemFlags;
 use ocl::Buffer;
+use ocl::Device;
 use ocl::Platform;
 use ocl::ProQue;
 use ocl::Result;
 
+use std::mem;
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
+use std::sync::Arc;
+
 use byteorder::{ByteOrder, LittleEndian};
 
+use log::info;
+
+use crate::{Throughput, WorkHasher};
+
+/// `CL_DEVICE_GLOBAL_FREE_MEMORY_AMD` from the `cl_amd_device_attribute_query` extension
+const GLOBAL_FREE_MEMORY_AMD: u32 = 0x4039;
+
+/// Metrics reported by the driver, `None` where it doesn't expose them
+pub struct GpuStats {
+    pub name: Option<String>,
+    pub memory_total: Option<u64>,
+    pub memory_free: Option<u64>,
+}
+
+/// How many results a GPU must have returned before its invalid work rate is judged
+const MIN_RESULTS: u64 = 10;
+
+/// The results a GPU returned and the nonces it tried, counted by its worker thread
+#[derive(Default)]
+pub struct GpuHealth {
+    valid_work: AtomicU64,
+    invalid_work: AtomicU64,
+    disabled: AtomicBool,
+    throughput: Throughput,
+}
+
+impl GpuHealth {
+    pub fn record(&self, valid: bool) {
+        let counter = if valid {
+            &self.valid_work
+        } else {
+            &self.invalid_work
+        };
+        counter.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub fn valid_work(&self) -> u64 {
+        self.valid_work.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn invalid_work(&self) -> u64 {
+        self.invalid_work.load(atomic::Ordering::Relaxed)
+    }
+
+    /// The share of results that were invalid, once there are enough of them to judge
+    pub fn invalid_rate(&self) -> Option<f64> {
+        let invalid = self.invalid_work();
+        let total = self.valid_work() + invalid;
+        if total < MIN_RESULTS {
+            return None;
+        }
+        Some(invalid as f64 / total as f64)
+    }
+
+    pub fn disabled(&self) -> bool {
+        self.disabled.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn disable(&self) {
+        self.disabled.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn throughput(&self) -> &Throughput {
+        &self.throughput
+    }
+}
+
+/// Queries a GPU's metrics and health while its worker thread owns the `Gpu`
+#[derive(Clone)]
+pub struct GpuMonitor {
+    device: Device,
+    health: Arc<GpuHealth>,
+}
+
+impl GpuMonitor {
+    pub fn health(&self) -> &GpuHealth {
+        &self.health
+    }
+
+    /// The health for the scheduler, which outlives the borrow of the monitor
+    pub fn shared_health(&self) -> Arc<GpuHealth> {
+        self.health.clone()
+    }
+
+    pub fn stats(&self) -> GpuStats {
+        let memory_total = match self.device.info(DeviceInfo::GlobalMemSize) {
+            Ok(DeviceInfoResult::GlobalMemSize(size)) => Some(size),
+            _ => None,
+        };
+        // Only AMD drivers report free memory, as a size_t in KiB
+        let memory_free = self
+            .device
+            .info_raw(GLOBAL_FREE_MEMORY_AMD)
+            .ok()
+            .and_then(|raw| raw.get(..mem::size_of::<usize>())?.try_into().ok())
+            .map(|raw| usize::from_ne_bytes(raw) as u64 * 1024);
+        GpuStats {
+            name: self.device.name().ok(),
+            memory_total,
+            memory_free,
+        }
+    }
+}
+
 pub struct Gpu {
+    device: Device,
+    health: Arc<GpuHealth>,
     kernel: ocl::Kernel,
     attempt: Buffer<u8>,
     result: Buffer<u8>,
     root: Buffer<u8>,
     threshold: Buffer<u8>,
+    threads: usize,
+    next_attempt: u64,
 }
 
 impl Gpu {
@@ -23,11 +136,24 @@ impl Gpu {
         device_idx: usize,
         threads: usize,
         local_work_size: Option<usize>,
+        hasher: &WorkHasher,
     ) -> Result<Gpu> {
         let mut prog_bldr = ProgramBuilder::new();
-        prog_bldr.src(include_str!("work.cl"));
-        let platforms = Platform::list();
-        if platforms.len() == 0 {
+        prog_bldr
+            .cmplr_def("WORK_HASH_LENGTH", hasher.length() as i32)
+            .cmplr_def("WORK_KEY_LENGTH", hasher.key().len() as i32)
+            .src(format!(
+                "__constant static uchar work_key[64] = {{{}}};\n\
+                 __constant static uchar work_personal[16] = {{{}}};\n",
+                cl_bytes(hasher.key(), 64),
+                cl_bytes(hasher.personal(), 16),
+            ))
+            .src(include_str!("work.cl"));
+        // Unlike Platform::list, doesn't panic when there is no OpenCL driver
+        let platforms: Vec<Platform> = ocl::core::get_platform_ids()
+            .map(|ids| ids.into_iter().map(Platform::new).collect())
+            .unwrap_or_default();
+        if platforms.is_empty() {
             return Err("No OpenCL platforms exist (check your drivers and OpenCL setup)".into());
         }
         if platform_idx >= platforms.len() {
@@ -46,7 +172,7 @@ impl Gpu {
             .build()?;
 
         let device = pro_que.device();
-        println!(
+        info!(
             "Initializing GPU: {} {}",
             device.vendor().unwrap_or_else(|_| "[unknown]".into()),
             device.name().unwrap_or_else(|_| "[unknown]".into())
@@ -88,29 +214,64 @@ impl Gpu {
         };
 
         let mut gpu = Gpu {
+            device,
+            health: Arc::default(),
             kernel,
             attempt,
             result,
             root,
-            threshold
+            threshold,
+            threads,
+            next_attempt: 0,
         };
         gpu.reset_bufs()?;
         Ok(gpu)
     }
 
+    pub fn monitor(&self) -> GpuMonitor {
+        GpuMonitor {
+            device: self.device,
+            health: self.health.clone(),
+        }
+    }
+
+    pub fn health(&self) -> &GpuHealth {
+        &self.health
+    }
+
+    /// How many nonces each dispatch of `run` tries
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
     pub fn reset_bufs(&mut self) -> Result<()> {
         self.result.write(&[0u8; 8] as &[u8]).enq()?;
         Ok(())
     }
 
-    pub fn set_task(&mut self, root: &[u8], threshold: &[u8]) -> Result<()> {
+    /// Sets the task to search for. Each dispatch of `run` then covers the
+    /// `threads` nonces following the previous one, starting at `seed`, so
+    /// that dispatches for the same task never overlap.
+    pub fn set_task(&mut self, root: &[u8], threshold: &[u8], seed: u64) -> Result<()> {
         self.reset_bufs()?;
         self.root.write(root).enq()?;
         self.threshold.write(threshold).enq()?;
+        self.next_attempt = seed;
         Ok(())
     }
 
-    pub fn run(&mut self, out: &mut [u8], attempt: u64) -> Result<bool> {
+    /// Runs a single dispatch at an unreachable threshold, keeping the device
+    /// and its buffers initialized while there is no real work.
+    pub fn warmup(&mut self) -> Result<()> {
+        self.set_task(&[0u8; 32], &[0xffu8; 32], 0)?;
+        let mut out = [0u8; 8];
+        self.run(&mut out)?;
+        self.reset_bufs()
+    }
+
+    pub fn run(&mut self, out: &mut [u8]) -> Result<bool> {
+        let attempt = self.next_attempt;
+        self.next_attempt = attempt.wrapping_add(self.threads as u64);
         let mut attempt_bytes = [0u8; 8];
         LittleEndian::write_u64(&mut attempt_bytes, attempt);
         self.attempt.write(&attempt_bytes as &[u8]).enq()?;
@@ -133,3 +294,29 @@ impl Gpu {
         Ok(success)
     }
 }
+
+/// The name of each OpenCL platform and of its devices, in the order `--gpu` indexes them
+pub fn list_devices() -> Vec<(String, Vec<String>)> {
+    // Unlike Platform::list, doesn't panic when there is no OpenCL driver
+    ocl::core::get_platform_ids()
+        .unwrap_or_default()
+        .into_iter()
+        .map(Platform::new)
+        .map(|platform| {
+            let devices = Device::list_all(platform)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|device| device.name().unwrap_or_else(|_| "[unknown]".into()))
+                .collect();
+            (platform.name().unwrap_or_else(|_| "[unknown]".into()), devices)
+        })
+        .collect()
+}
+
+/// Formats `bytes` zero padded to `len` as the body of an OpenCL array initializer
+fn cl_bytes(bytes: &[u8], len: usize) -> String {
+    (0..len)
+        .map(|i| bytes.get(i).copied().unwrap_or(0).to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,108 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use parking_lot::Mutex;
+
+/// A log file, rotated to `PATH.1` once it would grow past `max_size`
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: Option<u64>) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + len > max_size {
+                self.rotate()?;
+            }
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut backup = self.path.clone().into_os_string();
+        backup.push(".1");
+        fs::rename(&self.path, backup)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Writes info to stdout and warnings and errors to stderr, or everything to
+/// the log file if there is one.
+struct Logger {
+    file: Option<Mutex<LogFile>>,
+    /// Also write warnings and errors to stderr when logging to a file
+    errors_to_stderr: bool,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Only our own logs, not those of dependencies
+        metadata.level() <= Level::Info && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let is_error = record.level() <= Level::Warn;
+        let line = record.args().to_string();
+        match &self.file {
+            Some(file) => {
+                if let Err(err) = file.lock().write_line(&line) {
+                    eprintln!("Failed to write to log file: {}", err);
+                }
+                if is_error && self.errors_to_stderr {
+                    eprintln!("{}", line);
+                }
+            }
+            None if is_error => eprintln!("{}", line),
+            None => println!("{}", line),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().file.flush();
+        }
+    }
+}
+
+/// Installs the logger behind the `log` macros. Must be called once, before anything is logged.
+pub fn init(file: Option<PathBuf>, max_size: Option<u64>, errors_to_stderr: bool) -> io::Result<()> {
+    let file = match file {
+        Some(path) => Some(Mutex::new(LogFile::open(path, max_size)?)),
+        None => None,
+    };
+    log::set_boxed_logger(Box::new(Logger {
+        file,
+        errors_to_stderr,
+    }))
+    .expect("Logger already initialized");
+    log::set_max_level(LevelFilter::Info);
+    Ok(())
+}
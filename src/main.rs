@@ -1,49 +1,149 @@
+mod buffers;
+mod compression;
+mod connection;
+mod difficulty;
 mod gpu;
+mod logging;
+mod metrics;
+mod numa;
+mod precompute;
+mod ratelimit;
+mod reference;
+mod upstream;
+mod webhook;
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::Infallible;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::process;
-use std::sync::atomic::{self, AtomicBool};
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::thread;
-use std::time::Instant;
-use std::u64;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 use futures::channel::oneshot;
-use futures::future::{self, Future};
-use futures::TryFutureExt;
+use futures::future::{self, Either, Future};
 
 use hyper::{Body, Request, Response, Server, StatusCode};
 
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::sync::{mpsc, Notify, Semaphore};
+
 use serde_json::{json, Value};
 
 use rand::{Rng, SeedableRng};
 
 use rand_xorshift::XorShiftRng;
 
-use blake2::Blake2bVar;
+use blake2::Blake2bVarCore;
+
+use digest::core_api::{Buffer, UpdateCore, VariableOutputCore};
 
-use digest::{Update, VariableOutput};
+use parking_lot::{Condvar, Mutex, MutexGuard};
 
-use parking_lot::{Condvar, Mutex};
+use chrono::{DateTime, TimeZone, Utc};
 
-use chrono::{DateTime, Utc};
+use log::{error, info, warn};
 
-use gpu::Gpu;
+use connection::{Connection, RequestGuard};
+use gpu::{Gpu, GpuMonitor};
 
-fn work_value(root: [u8; 32], work: [u8; 8]) -> [u8; 32] {
-    let mut buf = [0u8; 32];
-    let mut hasher = Blake2bVar::new(buf.len()).expect("Unsupported hash length");
-    hasher.update(&work);
-    hasher.update(&root);
-    hasher.finalize_variable(&mut buf).unwrap();
-    buf
+/// The Blake2b parameters used to hash `work || root`.
+///
+/// The defaults (32 byte digest, no key, no personalization) match Nano and Vite.
+/// Digests shorter than 32 bytes are left aligned and zero padded, so they can
+/// still be compared against a 32 byte threshold.
+#[derive(Clone)]
+pub struct WorkHasher {
+    length: usize,
+    key: Vec<u8>,
+    personal: Vec<u8>,
+    core: Blake2bVarCore,
+    buffer: Buffer<Blake2bVarCore>,
 }
 
-#[inline]
-fn work_valid(root: [u8; 32], work: [u8; 8], threshold: [u8; 32]) -> (bool, [u8; 32]) {
-    let result_threshold = work_value(root, work);
-    (quick_greater_or_equal(result_threshold, threshold), result_threshold)
+impl WorkHasher {
+    fn new(length: usize, key: &[u8], personal: &[u8]) -> Result<WorkHasher, String> {
+        if length == 0 || length > 32 {
+            return Err(format!("Hash length must be between 1 and 32 (got {})", length));
+        }
+        if key.len() > 64 {
+            return Err(format!("Hash key is too long ({} bytes, max 64)", key.len()));
+        }
+        if personal.len() > 16 {
+            return Err(format!(
+                "Hash personalization is too long ({} bytes, max 16)",
+                personal.len()
+            ));
+        }
+        let mut core = Blake2bVarCore::new_with_params(&[], personal, key.len(), length);
+        let mut buffer = Buffer::<Blake2bVarCore>::default();
+        if !key.is_empty() {
+            // A keyed Blake2b absorbs the key zero padded to a full block first
+            let mut key_block = [0u8; 128];
+            key_block[..key.len()].copy_from_slice(key);
+            buffer.digest_blocks(&key_block, |blocks| core.update_blocks(blocks));
+        }
+        Ok(WorkHasher {
+            length,
+            key: key.to_vec(),
+            personal: personal.to_vec(),
+            core,
+            buffer,
+        })
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn personal(&self) -> &[u8] {
+        &self.personal
+    }
+
+    fn work_value(&self, root: [u8; 32], work: [u8; 8]) -> [u8; 32] {
+        let mut core = self.core.clone();
+        let mut buffer = self.buffer.clone();
+        buffer.digest_blocks(&work, |blocks| core.update_blocks(blocks));
+        buffer.digest_blocks(&root, |blocks| core.update_blocks(blocks));
+        let mut full = Default::default();
+        core.finalize_variable_core(&mut buffer, &mut full);
+        let mut buf = [0u8; 32];
+        buf[..self.length].copy_from_slice(&full[..self.length]);
+        buf
+    }
+
+    #[inline]
+    fn work_valid(
+        &self,
+        root: [u8; 32],
+        work: [u8; 8],
+        threshold: [u8; 32],
+    ) -> (bool, [u8; 32]) {
+        let result_threshold = self.work_value(root, work);
+        (quick_greater_or_equal(result_threshold, threshold), result_threshold)
+    }
+
+    /// Checks work again with the reference Blake2b, for `--double-check`
+    fn reference_valid(&self, root: [u8; 32], work: [u8; 8], threshold: [u8; 32]) -> bool {
+        let mut input = [0u8; 40];
+        input[..8].copy_from_slice(&work);
+        input[8..].copy_from_slice(&root);
+        let mut value = [0u8; 32];
+        reference::blake2b(&mut value[..self.length], &self.key, &self.personal, &input);
+        quick_greater_or_equal(value, threshold)
+    }
 }
 
 fn quick_greater_or_equal(x: [u8; 32], y: [u8; 32]) -> bool {
@@ -58,55 +158,955 @@ fn quick_greater_or_equal(x: [u8; 32], y: [u8; 32]) -> bool {
     true
 }
 
+/// Compares two byte strings without exiting early on the first difference
+fn constant_time_eq(x: &[u8], y: &[u8]) -> bool {
+    if x.len() != y.len() {
+        return false;
+    }
+    x.iter().zip(y).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
 enum WorkError {
-    Canceled,
+    /// Cancelled by `work_cancel`
+    ClientCancel,
+    /// Gave way to another request, e.g. `--precompute-file` work to a client's
+    Superseded,
+    /// Cancelled because the server is shutting down
+    Shutdown,
+    /// Not generated within `--work-timeout`
+    Timeout,
     Errored,
+    /// With the best work found so far if the request allowed partial work
+    ExhaustedAttempts(Option<[u8; 8]>),
+}
+
+/// How a worker solved a task, for `"verbose": true`
+#[derive(Clone, Copy)]
+struct Solved {
+    worker: Worker,
+    /// Nonces tried on the task by all workers, give or take the batches in flight
+    attempts: u64,
+    queued: Duration,
+    computing: Duration,
+}
+
+/// Where `find_work` got work from
+#[derive(Clone, Copy)]
+enum WorkSource {
+    Trivial,
+    Stored,
+    Upstream,
+    /// The `--peer` with this index, which answered first with `--fanout`
+    Peer(usize),
+    Generated(Solved),
+}
+
+/// Work and how it was found, or why it wasn't
+type WorkResult = Result<([u8; 8], Solved), WorkError>;
+
+type WorkCallback = oneshot::Sender<WorkResult>;
+
+/// Queued or running work, resolving with its result. Dropping it before then, e.g. when the
+/// client disconnects, cancels the work so the workers don't keep at it for nobody.
+struct PendingWork {
+    result: Option<oneshot::Receiver<WorkResult>>,
+    work_state: Arc<(Mutex<WorkState>, Condvar)>,
+    /// Past this, the work is cancelled and resolves with `WorkError::Timeout`
+    deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl Future for PendingWork {
+    type Output = WorkResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let result = self.result.as_mut().expect("PendingWork polled after completion");
+        match Pin::new(result).poll(cx) {
+            Poll::Ready(result) => {
+                self.result = None;
+                Poll::Ready(result.unwrap_or(Err(WorkError::Errored)))
+            }
+            Poll::Pending => {
+                let timed_out = self.deadline.as_mut().is_some_and(|deadline| deadline.as_mut().poll(cx).is_ready());
+                if !timed_out {
+                    return Poll::Pending;
+                }
+                // The receiver goes first, like when dropped, so the work shows as abandoned
+                self.result = None;
+                self.work_state.0.lock().drop_abandoned(&self.work_state.1);
+                Poll::Ready(Err(WorkError::Timeout))
+            }
+        }
+    }
+}
+
+impl Drop for PendingWork {
+    fn drop(&mut self) {
+        // The receiver goes first, so the work's callback shows it as cancelled
+        if self.result.take().is_some() {
+            self.work_state.0.lock().drop_abandoned(&self.work_state.1);
+        }
+    }
+}
+
+/// A worker thread, which a request can pin its work to with `worker` (e.g. `gpu:1`)
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Worker {
+    Cpu(usize),
+    Gpu(usize),
+}
+
+impl std::fmt::Display for Worker {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Worker::Cpu(i) => write!(f, "cpu:{}", i),
+            Worker::Gpu(i) => write!(f, "gpu:{}", i),
+        }
+    }
+}
+
+struct QueuedWork {
+    root: [u8; 32],
+    threshold: [u8; 32],
+    callback: WorkCallback,
+    /// Who asked for it, for `--fair-queue`. `None` for the server's own work.
+    client: Option<IpAddr>,
+    /// Whether to keep track of the best work found, to return it if the attempts run out
+    allow_partial: bool,
+    /// The only worker allowed to generate it, if pinned
+    worker: Option<Worker>,
+    queued_at: Instant,
 }
 
 #[derive(Default)]
 struct WorkState {
     root: [u8; 32],
     threshold: [u8; 32],
-    callback: Option<oneshot::Sender<Result<[u8; 8], WorkError>>>,
+    callback: Option<WorkCallback>,
     task_complete: Arc<AtomicBool>,
+    /// Nonces tried so far on the current task, counted against `--max-attempts`
+    /// and reported with `"verbose": true`
+    attempts: Arc<AtomicU64>,
+    /// Workers that gave up on the current task (e.g. a GPU erroring repeatedly)
     unsuccessful_workers: usize,
+    /// Workers that crashed and will never pick up a task again
+    dead_workers: usize,
+    /// Workers waiting on the Condvar for a task, rather than spinning
+    parked: HashSet<Worker>,
+    dead: HashSet<Worker>,
+    n_workers: usize,
+    /// Whether the fallback CPU threads help with the current task, after a GPU failed to set it up
+    fallback: bool,
+    /// Fallback CPU threads, not counted in `n_workers` as they are idle unless `fallback` is set
+    n_fallback_workers: usize,
+    /// Whether the current task is from `--precompute-file`, and gives way to any request
+    precomputing: bool,
+    /// Whether CPU threads track the best work for the current task, which costs a comparison per nonce
+    track_best: bool,
+    /// Whether the current task's request takes the best work found if its attempts run out.
+    /// Unlike `track_best`, not set by `--track-progress`.
+    allow_partial: bool,
+    /// Set with `--track-progress`, tracks the best work of every task for `status`
+    track_progress: bool,
+    /// The best work found for the current task that doesn't meet its threshold, and its value
+    best: Option<([u8; 8], [u8; 32])>,
+    /// When the current task was queued and started, for `queue_dump`
+    task_times: Option<(Instant, Instant)>,
+    task_client: Option<IpAddr>,
+    /// The only worker on the current task, if the request pinned it
+    task_worker: Option<Worker>,
+    /// The GPU the current task was given to as the fastest, until it gives up on it
+    task_assignee: Option<Worker>,
+    /// Set with `--all-workers`, every worker joins every task instead
+    all_workers: bool,
+    /// The health of each GPU in `--gpu` order, for the hashrate the scheduler picks by
+    gpu_health: Vec<Arc<gpu::GpuHealth>>,
     random_mode: bool,
-    future_work: Vec<([u8; 32], [u8; 32], oneshot::Sender<Result<[u8; 8], WorkError>>)>,
+    /// Round-robin between clients instead of taking the oldest (or a random) work
+    fair_queue: bool,
+    /// Increases with every task started, to tell which clients were served least recently
+    serial: u64,
+    /// When each client with queued work was last served, under `--fair-queue`
+    last_served: HashMap<Option<IpAddr>, u64>,
+    future_work: Vec<QueuedWork>,
+    /// Set by `cancel_all`, after which no work is queued
+    shutting_down: bool,
+    /// Where the phases of each task are timed, with `--phase-metrics`
+    phase_metrics: Option<Arc<metrics::Metrics>>,
+    /// Whether a worker picked up the current task yet, for the `assign` phase
+    assigned: bool,
 }
 
 impl WorkState {
+    /// Queues work, or rejects it once shutting down, as nothing would answer it after the
+    /// queue was drained
+    fn enqueue(&mut self, work: QueuedWork) {
+        if self.shutting_down {
+            let _ = work.callback.send(Err(WorkError::Shutdown));
+        } else {
+            self.future_work.push(work);
+        }
+    }
+
+    /// Whether `worker` should be working on the current task
+    fn runs_task(&self, worker: Worker) -> bool {
+        self.callback.is_some()
+            && self.task_worker.is_none_or(|pinned| pinned == worker)
+            && self.task_assignee.is_none_or(|assignee| assignee == worker)
+    }
+
+    /// The GPU to give a task to alone: the fastest parked one by measured hashrate, or the
+    /// fastest if none is parked yet. `None` puts every worker on it, under `--all-workers`
+    /// or until a GPU's hashrate was measured.
+    fn fastest_gpu(&self) -> Option<Worker> {
+        if self.all_workers {
+            return None;
+        }
+        self.gpu_health
+            .iter()
+            .enumerate()
+            .map(|(i, health)| (Worker::Gpu(i), health))
+            .filter(|(worker, health)| !self.dead.contains(worker) && !health.disabled())
+            .map(|(worker, health)| (self.parked.contains(&worker), health.throughput().hashrate(), worker))
+            .filter(|&(_, hashrate, _)| hashrate > 0.0)
+            .max_by(|(parked_a, a, _), (parked_b, b, _)| parked_a.cmp(parked_b).then(a.total_cmp(b)))
+            .map(|(_, _, worker)| worker)
+    }
+
+    /// Counts a worker out for good
+    fn mark_dead(&mut self, worker: Worker) {
+        self.dead_workers += 1;
+        self.dead.insert(worker);
+    }
+
+    /// Times the `assign` phase, once the first worker picked up the current task
+    fn record_assigned(&mut self) {
+        if let (false, Some(metrics), Some((_, started_at))) =
+            (self.assigned, &self.phase_metrics, self.task_times)
+        {
+            metrics.record_phase("assign", started_at.elapsed());
+        }
+        self.assigned = true;
+    }
+
+    /// Answers the current task with `work`, found by `worker` after its last `tried` nonces
+    fn solve(&mut self, work: [u8; 8], worker: Worker, tried: u64, cond_var: &Condvar) {
+        if let Some(callback) = self.callback.take() {
+            let (queued_at, started_at) = self.task_times.unwrap_or_else(|| {
+                let now = Instant::now();
+                (now, now)
+            });
+            let solved = Solved {
+                worker,
+                attempts: self.attempts.load(atomic::Ordering::Relaxed) + tried,
+                queued: started_at - queued_at,
+                computing: started_at.elapsed(),
+            };
+            let _ = callback.send(Ok((work, solved)));
+            self.set_task(cond_var);
+        }
+    }
+
     fn set_task(&mut self, cond_var: &Condvar) {
         if self.callback.is_none() {
             self.task_complete.store(true, atomic::Ordering::Relaxed);
-            if self.future_work.len() > 0 {
-                let max_range = if self.random_mode {
-                    self.future_work.len()
+            // The previous task is over, whatever the outcome. Taken so it's only counted once.
+            if let (Some(metrics), Some((_, started_at))) = (&self.phase_metrics, self.task_times.take()) {
+                if !self.precomputing {
+                    metrics.record_phase("compute", started_at.elapsed());
+                }
+            }
+            if self.dead_workers >= self.n_workers {
+                // Nothing is left to run the queued work, so fail it rather than leaving it hanging
+                for work in self.future_work.drain(..) {
+                    let _ = work.callback.send(Err(WorkError::Errored));
+                }
+                return;
+            }
+            while !self.future_work.is_empty() {
+                let i = if self.fair_queue {
+                    self.next_fair()
+                } else if self.random_mode {
+                    rand::thread_rng().gen_range(0..self.future_work.len())
                 } else {
-                    1
+                    0
                 };
-                let i = rand::thread_rng().gen_range(0..max_range);
-                let (root, threshold, callback) = self.future_work.remove(i);
-                self.root = root;
-                self.threshold = threshold;
-                self.callback = Some(callback);
+                let work = self.future_work.remove(i);
+                if work.worker.is_some_and(|worker| self.dead.contains(&worker)) {
+                    // The worker died while the work was queued
+                    let _ = work.callback.send(Err(WorkError::Errored));
+                    continue;
+                }
+                self.root = work.root;
+                self.threshold = work.threshold;
+                self.callback = Some(work.callback);
+                self.allow_partial = work.allow_partial;
+                self.track_best = work.allow_partial || self.track_progress;
+                self.best = None;
+                self.task_times = Some((work.queued_at, Instant::now()));
+                self.assigned = false;
+                if let Some(metrics) = &self.phase_metrics {
+                    metrics.record_phase("queue", work.queued_at.elapsed());
+                }
+                self.task_client = work.client;
+                self.task_worker = work.worker;
+                // Only CPU threads keep the best work for allow_partial
+                self.task_assignee = None;
+                if work.worker.is_none() && !work.allow_partial {
+                    self.task_assignee = self.fastest_gpu();
+                }
                 self.task_complete = Arc::new(AtomicBool::new(false));
+                self.attempts = Arc::new(AtomicU64::new(0));
+                self.fallback = false;
+                self.precomputing = false;
+                // Every idle worker joins the new task, or checks whether it was given it, so
+                // all of them need waking. Each only holds the lock to copy the task. Handing
+                // the wakeup from one worker to the next wakes far fewer threads, but could not
+                // reach a GPU assignee without waking the workers ahead of it, and only beat
+                // this once there were many more threads than cores.
+                cond_var.notify_all();
+                break;
+            }
+        }
+    }
+
+    /// Picks the oldest work of the client served least recently, and marks it served
+    fn next_fair(&mut self) -> usize {
+        let last_served = &self.last_served;
+        let i = (0..self.future_work.len())
+            .min_by_key(|&i| {
+                let client = self.future_work[i].client;
+                (last_served.get(&client).copied().unwrap_or(0), i)
+            })
+            .expect("No queued work");
+        self.serial += 1;
+        let client = self.future_work[i].client;
+        // Forget clients with nothing queued, they're served first if they come back
+        let future_work = &self.future_work;
+        self.last_served
+            .retain(|client, _| future_work.iter().any(|work| work.client == *client));
+        self.last_served.insert(client, self.serial);
+        i
+    }
+
+    /// Drops the queued work nobody waits for anymore, and the current task if nobody does
+    fn drop_abandoned(&mut self, cond_var: &Condvar) {
+        self.future_work.retain(|work| !work.callback.is_canceled());
+        if self.callback.as_ref().is_some_and(|callback| callback.is_canceled()) {
+            info!(
+                "Nobody is waiting for {} anymore, cancelling it",
+                hex::encode_upper(self.root)
+            );
+            self.callback = None;
+            self.set_task(cond_var);
+        }
+    }
+
+    /// Cancels the current task and everything queued. Each callback is taken out of the
+    /// state under the lock before being answered, so a worker finding the work at the same
+    /// time can't answer it again, and nothing can be queued afterwards.
+    fn cancel_all(&mut self) {
+        self.shutting_down = true;
+        for work in self.future_work.drain(..) {
+            let _ = work.callback.send(Err(WorkError::Shutdown));
+        }
+        if let Some(callback) = self.callback.take() {
+            let _ = callback.send(Err(WorkError::Shutdown));
+        }
+        self.task_complete.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Fails the current task if every worker has either given up on it or crashed.
+    /// While at least one worker (e.g. a CPU thread next to a failed GPU) keeps going,
+    /// the task is left running and resolves once that worker finds the work. A task pinned
+    /// to a worker fails as soon as that worker gives up.
+    fn fail_if_no_workers(&mut self, cond_var: &Condvar) {
+        if let Some(assignee) = self.task_assignee {
+            if self.unsuccessful_workers > 0 || self.dead.contains(&assignee) {
+                // The other workers take over from the GPU the task was given to
+                self.task_assignee = None;
                 cond_var.notify_all();
             }
         }
+        let fallback_workers = if self.fallback {
+            self.n_fallback_workers
+        } else {
+            0
+        };
+        let gave_up = match self.task_worker {
+            // Nothing else is on a pinned task, so any worker that gave up on it is the pinned one
+            Some(worker) => self.unsuccessful_workers > 0 || self.dead.contains(&worker),
+            None => self.unsuccessful_workers + self.dead_workers >= self.n_workers + fallback_workers,
+        };
+        if gave_up {
+            if let Some(callback) = self.callback.take() {
+                let _ = callback.send(Err(WorkError::Errored));
+                self.set_task(cond_var);
+            }
+        }
+    }
+}
+
+/// Runs a worker loop, no longer counting on the worker if it panics
+fn run_worker<F: FnOnce()>(
+    name: String,
+    id: Worker,
+    fallback: bool,
+    work_state: Arc<(Mutex<WorkState>, Condvar)>,
+    worker: F,
+) {
+    if panic::catch_unwind(AssertUnwindSafe(worker)).is_err() {
+        error!("{} crashed, it will no longer generate work", name);
+        let mut state = work_state.0.lock();
+        if fallback {
+            state.n_fallback_workers -= 1;
+        } else {
+            state.mark_dead(id);
+        }
+        state.fail_if_no_workers(&work_state.1);
+    }
+}
+
+/// Adds `n` tried nonces to a task's attempts, and fails the task once they exceed `max_attempts`
+fn count_attempts(
+    work_state: &(Mutex<WorkState>, Condvar),
+    attempts: &Arc<AtomicU64>,
+    n: u64,
+    max_attempts: Option<u64>,
+) {
+    let attempts_before = attempts.fetch_add(n, atomic::Ordering::Relaxed);
+    if max_attempts.is_none_or(|max_attempts| attempts_before.saturating_add(n) <= max_attempts) {
+        return;
+    }
+    let mut state = work_state.0.lock();
+    // The counter is replaced with every task, so this can't fail a newer one
+    if Arc::ptr_eq(attempts, &state.attempts) {
+        if let Some(callback) = state.callback.take() {
+            let best = state.best.filter(|_| state.allow_partial).map(|(work, _)| work);
+            let _ = callback.send(Err(WorkError::ExhaustedAttempts(best)));
+            state.set_task(&work_state.1);
+        }
+    }
+}
+
+/// Waits for the work state to change, for at most `timeout`, counted in `parked` meanwhile.
+/// Returns whether it timed out.
+fn park(state: &mut MutexGuard<WorkState>, condvar: &Condvar, worker: Worker, timeout: Option<Duration>) -> bool {
+    state.parked.insert(worker);
+    let timed_out = match timeout {
+        Some(timeout) => condvar.wait_for(state, timeout).timed_out(),
+        None => {
+            condvar.wait(state);
+            false
+        }
+    };
+    state.parked.remove(&worker);
+    timed_out
+}
+
+/// The settings of the CPU threads
+struct CpuConfig {
+    /// Nonces tried between checks for a new task (`--cpu-batch-size`)
+    batch_size: u64,
+    double_check: bool,
+    max_attempts: Option<u64>,
+    numa_node: Option<(usize, Arc<[usize]>)>,
+}
+
+/// Starts CPU thread `cpu_i`. A `fallback` one only helps with the tasks a GPU failed to set up.
+fn spawn_cpu_worker(
+    cpu_i: usize,
+    fallback: bool,
+    name: String,
+    work_state: &Arc<(Mutex<WorkState>, Condvar)>,
+    throughput: &Arc<Throughput>,
+    hasher: &Arc<WorkHasher>,
+    config: &CpuConfig,
+) -> thread::JoinHandle<()> {
+    let crash_state = work_state.clone();
+    let work_state = work_state.clone();
+    let throughput = throughput.clone();
+    let hasher = hasher.clone();
+    let cpu_batch_size = config.batch_size;
+    let double_check = config.double_check;
+    let max_attempts = config.max_attempts;
+    let numa_node = config.numa_node.clone();
+    let mut rng =
+        XorShiftRng::from_rng(rand::thread_rng()).expect("Failed to create XorShiftRng");
+    let mut root = [0u8; 32];
+    let mut threshold = [0u8; 32];
+    let mut task_complete = Arc::new(AtomicBool::new(true));
+    let mut attempts = Arc::new(AtomicU64::new(0));
+    let mut track_best = false;
+    let mut best: Option<([u8; 8], [u8; 32])> = None;
+    let worker = move || loop {
+        if task_complete.load(atomic::Ordering::Relaxed) {
+            let mut state = work_state.0.lock();
+            while !state.runs_task(Worker::Cpu(cpu_i)) || (fallback && !state.fallback) {
+                park(&mut state, &work_state.1, Worker::Cpu(cpu_i), None);
+            }
+            root = state.root;
+            threshold = state.threshold;
+            task_complete = state.task_complete.clone();
+            attempts = state.attempts.clone();
+            state.record_assigned();
+            track_best = state.track_best;
+            best = None;
+        }
+        let mut out: [u8; 8] = rng.gen();
+        let mut tried = 0;
+        let mut improved = false;
+        for _ in 0..cpu_batch_size {
+            tried += 1;
+            let (valid, value) = hasher.work_valid(root, out, threshold);
+            if track_best && best.is_none_or(|(_, best)| value > best) {
+                best = Some((out, value));
+                improved = true;
+            }
+            if valid && double_check && !hasher.reference_valid(root, out, threshold) {
+                error!(
+                    "Work {} for {} doesn't pass the double check, discarding it",
+                    hex::encode(out),
+                    hex::encode_upper(root),
+                );
+            } else if valid {
+                let mut state = work_state.0.lock();
+                if root == state.root {
+                    state.solve(out, Worker::Cpu(cpu_i), tried, &work_state.1);
+                }
+                break;
+            }
+            for byte in out.iter_mut() {
+                *byte = byte.wrapping_add(1);
+                if *byte != 0 {
+                    // We did not overflow
+                    break;
+                }
+            }
+        }
+        throughput.add(tried);
+        if let (true, Some((_, value))) = (improved, best) {
+            let mut state = work_state.0.lock();
+            // The attempts counter is replaced with every task, so this is still the same one
+            if Arc::ptr_eq(&attempts, &state.attempts)
+                && state.best.is_none_or(|(_, best)| value > best)
+            {
+                state.best = best;
+            }
+        }
+        count_attempts(&work_state, &attempts, tried, max_attempts);
+    };
+    thread::spawn(move || {
+        if let Some((node, cpus)) = numa_node {
+            if let Err(err) = numa::bind_current_thread(node, &cpus) {
+                warn!("Failed to bind {} to NUMA node {}: {}", name, node, err);
+            }
+        }
+        run_worker(name, Worker::Cpu(cpu_i), fallback, crash_state, worker)
+    })
+}
+
+/// The most works a single `work_generate` can ask for with `count`
+const MAX_WORK_COUNT: u64 = 64;
+
+/// How many random roots `--self-test` generates work for
+const SELF_TEST_ROOTS: usize = 8;
+
+/// About 65536 tries per work, so the self test is quick even on a single CPU thread
+const SELF_TEST_THRESHOLD: [u8; 32] = {
+    let mut threshold = [0u8; 32];
+    threshold[0] = 0xff;
+    threshold[1] = 0xff;
+    threshold
+};
+
+/// How long `--self-test` waits for each work
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A Blake2b-256 of `work || root` computed with a reference implementation
+const SELF_TEST_KNOWN_ROOT: [u8; 32] = [
+    0x71, 0x8c, 0xc2, 0x12, 0x1c, 0x3e, 0x64, 0x10, 0x59, 0xbc, 0x1c, 0x2c, 0xfc, 0x45, 0x66, 0x6c,
+    0x99, 0xe8, 0xae, 0x92, 0x2f, 0x7a, 0x80, 0x7b, 0x7d, 0x07, 0xb6, 0x2c, 0x99, 0x5d, 0x79, 0xe2,
+];
+const SELF_TEST_KNOWN_WORK: [u8; 8] = [0xbc, 0xa6, 0x86, 0x07, 0xf0, 0x9e, 0xf2, 0x2b];
+const SELF_TEST_KNOWN_VALUE: [u8; 32] = [
+    0x08, 0xd8, 0x37, 0x4b, 0x7c, 0x88, 0x31, 0x3f, 0xe7, 0x18, 0xe7, 0x2f, 0x5f, 0xe2, 0x57, 0xb0,
+    0x31, 0xe2, 0xa0, 0xff, 0x22, 0x1b, 0x92, 0x5f, 0x81, 0x6d, 0xef, 0xe1, 0x7f, 0x85, 0x2e, 0xe5,
+];
+
+/// How often the `--precompute-file` feed checks whether the workers are idle
+const PRECOMPUTE_IDLE_POLL: Duration = Duration::from_millis(100);
+
+/// How often the hashrate average is updated
+const HASHRATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Roughly how far back the hashrate average looks, in seconds
+const HASHRATE_WINDOW: f64 = 10.0;
+
+/// How long `--startup-bench` generates work for
+const STARTUP_BENCH_TIME: Duration = Duration::from_secs(5);
+
+/// Nonces tried by some workers, and an exponential moving average of how many per second
+#[derive(Default)]
+struct Throughput {
+    hashes: AtomicU64,
+    total: AtomicU64,
+    hashrate: Mutex<f64>,
+}
+
+impl Throughput {
+    fn add(&self, hashes: u64) {
+        self.hashes.fetch_add(hashes, atomic::Ordering::Relaxed);
+        self.total.fetch_add(hashes, atomic::Ordering::Relaxed);
+    }
+
+    /// All the nonces tried since startup
+    fn total(&self) -> u64 {
+        self.total.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Folds the hashes tried over the last `elapsed` into the average
+    fn update(&self, elapsed: Duration) {
+        let hashes = self.hashes.swap(0, atomic::Ordering::Relaxed);
+        let rate = hashes as f64 / elapsed.as_secs_f64();
+        let alpha = 1.0 - (-elapsed.as_secs_f64() / HASHRATE_WINDOW).exp();
+        let mut hashrate = self.hashrate.lock();
+        *hashrate += alpha * (rate - *hashrate);
+    }
+
+    fn hashrate(&self) -> f64 {
+        *self.hashrate.lock()
+    }
+}
+
+/// How many recent generation durations are kept for the status average
+const RECENT_DURATIONS: usize = 100;
+
+/// Lifetime counters reported by `status` and cleared by `reset_stats`
+struct Stats {
+    since: DateTime<Utc>,
+    work_requests: u64,
+    work_generated: u64,
+    work_cancelled: u64,
+    work_failed: u64,
+    recent_durations: VecDeque<u128>,
+}
+
+impl Stats {
+    fn new() -> Stats {
+        Stats {
+            since: Utc::now(),
+            work_requests: 0,
+            work_generated: 0,
+            work_cancelled: 0,
+            work_failed: 0,
+            recent_durations: VecDeque::with_capacity(RECENT_DURATIONS),
+        }
+    }
+
+    fn record_duration(&mut self, millis: u128) {
+        if self.recent_durations.len() == RECENT_DURATIONS {
+            self.recent_durations.pop_front();
+        }
+        self.recent_durations.push_back(millis);
+    }
+
+    fn average_duration(&self) -> Option<u128> {
+        if self.recent_durations.is_empty() {
+            return None;
+        }
+        Some(self.recent_durations.iter().sum::<u128>() / self.recent_durations.len() as u128)
     }
 }
 
 #[derive(Clone)]
 struct RpcService {
     work_state: Arc<(Mutex<WorkState>, Condvar)>,
+    hasher: Arc<WorkHasher>,
+    connections: Arc<AtomicUsize>,
+    rpc_path: Arc<str>,
+    api_key: Option<Arc<str>>,
+    /// The actions that need the API key, if not all of them (`--auth-actions`)
+    auth_actions: Option<Arc<[&'static str]>>,
+    stats: Arc<Mutex<Stats>>,
+    base_threshold: [u8; 32],
+    /// Thresholds that requests can give by name (`--difficulty-name`)
+    difficulty_names: Arc<HashMap<String, [u8; 32]>>,
+    /// `Little` with `--raw-work`
+    default_endian: Endian,
+    gpus: Arc<Vec<GpuMonitor>>,
+    /// CPU threads that `worker` may name, not counting the fallback ones
+    cpu_threads: usize,
+    /// Set with `--allow-remote-shutdown`, which requires `--api-key`
+    allow_remote_shutdown: bool,
+    shutdown: Arc<Notify>,
+    throughput: Arc<Throughput>,
+    /// Set with `--enable-compression`
+    compression: bool,
+    /// Weaker thresholds requested for `work_generate` are raised to this (`--min-multiplier`)
+    min_threshold: Option<[u8; 32]>,
+    /// Harder thresholds requested for `work_generate` are rejected (`--max-generation-multiplier`)
+    max_threshold: Option<[u8; 32]>,
+    /// Set with `--jsonrpc`
+    jsonrpc: bool,
+    /// Set with `--numeric-json`
+    numeric_json: bool,
+    /// Set with `--strict-content-type`
+    strict_content_type: bool,
+    /// Set with `--strict-http-status`
+    strict_http_status: bool,
+    /// Work generated from `--precompute-file` or for earlier requests
+    precomputed: Arc<precompute::Cache>,
+    /// Set with `--max-benchmark-count`
+    max_benchmark_count: u64,
+    metrics: Arc<metrics::Metrics>,
+    /// Where responses are serialized (`--response-buffers`)
+    buffers: Arc<buffers::BufferPool>,
+    /// Set with `--phase-metrics`
+    phase_metrics: bool,
+    /// How long reading a request or writing a response may take (`--io-timeout`)
+    io_timeout: Option<Duration>,
+    /// `work_generate` taking longer than this is logged as a warning (`--slow-request-ms`)
+    slow_request: Option<Duration>,
+    /// How long a work may be queued and generated before it's given up (`--work-timeout`)
+    work_timeout: Option<Duration>,
+    readiness: Arc<Readiness>,
+    /// Set with `--disable-action`
+    disabled_actions: Arc<[&'static str]>,
+    /// Requests allowed in flight on one connection (`--max-in-flight`)
+    max_in_flight: usize,
+    /// Above this rate of requests, `work_generate` is rejected (`--max-request-rate-global`)
+    request_rate: Option<Arc<ratelimit::RequestRate>>,
+    /// Where `work_generate` goes when the workers fail (`--upstream`)
+    upstream: Option<Arc<upstream::Upstream>>,
+    /// Work servers that `work_generate` is raced against (`--peer` with `--fanout`)
+    peers: Arc<[upstream::Upstream]>,
+    /// Where generated work is announced (`--webhook`)
+    webhook: Option<Arc<webhook::Webhook>>,
+    /// The settings resolved at startup, for `config_dump`
+    config: Arc<Value>,
+    activity: Arc<Activity>,
+}
+
+/// When the server was last busy, for `--idle-shutdown-secs`
+struct Activity {
+    /// Requests being answered
+    requests: AtomicUsize,
+    /// When the last request was answered, or the server started
+    last_request: Mutex<Instant>,
+}
+
+/// Served on GET, next to the RPC path
+const VERSION_PATH: &str = "/version";
+
+/// Served on GET in the Prometheus text format, next to the RPC path
+const METRICS_PATH: &str = "/metrics";
+
+/// Served on GET without the API key, always `200 OK` while the process is up
+const HEALTH_PATH: &str = "/health";
+
+/// Served on GET without the API key, `503 Service Unavailable` until the GPUs are usable
+const READY_PATH: &str = "/ready";
+
+#[derive(PartialEq, Eq)]
+enum Route {
+    Rpc,
+    Version,
+    Metrics,
+    Health,
+    Ready,
+    NotFound,
+}
+
+/// Counts down the GPUs that have yet to complete their first dispatch
+struct Readiness {
+    pending: AtomicUsize,
+}
+
+impl Readiness {
+    fn new(gpus: usize) -> Readiness {
+        Readiness {
+            pending: AtomicUsize::new(gpus),
+        }
+    }
+
+    fn gpu_ready(&self) {
+        if self.pending.fetch_sub(1, atomic::Ordering::Relaxed) == 1 {
+            info!("All GPUs initialized, ready for work");
+        }
+    }
+
+    fn ready(&self) -> bool {
+        self.pending.load(atomic::Ordering::Relaxed) == 0
+    }
+}
+
+/// Actions as labeled in `/metrics`. Others are labeled `unknown`, so clients can't add labels.
+const ACTIONS: &[&str] = &[
+    "work_generate",
+    "work_cancel",
+    "work_validate",
+    "work_peek",
+    "benchmark",
+    "status",
+    "reset_stats",
+    "shutdown",
+    "version",
+    "queue_dump",
+    "config_dump",
+    "health",
+    "ready",
+];
+
+/// The on/off options shown by `config_dump`
+const CONFIG_FLAGS: &[&str] = &[
+    "raw_work",
+    "jsonrpc",
+    "numeric_json",
+    "enable_compression",
+    "strict_content_type",
+    "strict_http_status",
+    "http2",
+    "fanout",
+    "allow_remote_shutdown",
+    "shuffle",
+    "fair_queue",
+    "all_workers",
+    "double_check",
+    "track_progress",
+    "phase_metrics",
+    "warmup",
+];
+
+/// The action a response answered, for `/metrics`
+#[derive(Clone, Copy)]
+struct Action(&'static str);
+
+impl Action {
+    fn of(request: Option<&Value>) -> Action {
+        let action = request.and_then(|request| request.get("action"));
+        Action(
+            ACTIONS
+                .iter()
+                .copied()
+                .find(|&name| action.is_some_and(|action| action == name))
+                .unwrap_or("unknown"),
+        )
+    }
+}
+
+/// Describes this build, for `GET /version` and the `version` action
+fn version_info() -> Value {
+    let built_at = env!("BUILD_TIMESTAMP")
+        .parse()
+        .ok()
+        .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+        .map(|time| time.to_rfc3339());
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("BUILD_COMMIT"),
+        "built_at": built_at,
+        // GPU support is always compiled in, and the Blake2b hashing isn't SIMD accelerated
+        "features": ["opencl"],
+    })
+}
+
+/// How many characters of an offending value parse errors echo back
+const MAX_ECHOED_VALUE: usize = 80;
+
+/// Adds the offending field and its value, truncated, to a parse error
+fn bad_field(mut err: Value, field: &str, value: &Value) -> Value {
+    let got = match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    };
+    err["field"] = json!(field);
+    err["got"] = json!(match got.char_indices().nth(MAX_ECHOED_VALUE) {
+        Some((end, _)) => format!("{}...", &got[..end]),
+        None => got,
+    });
+    err
+}
+
+/// The HTTP status for errors answered with `200 OK` by default, under `--strict-http-status`
+fn strict_status(code: &str) -> Option<StatusCode> {
+    Some(match code {
+        "CANCELLED" | "SUPERSEDED" => StatusCode::CONFLICT,
+        "SHUTTING_DOWN" | "WORK_TIMEOUT" => StatusCode::SERVICE_UNAVAILABLE,
+        "GENERATION_FAILED" => StatusCode::INTERNAL_SERVER_ERROR,
+        "EXHAUSTED_ATTEMPTS" => StatusCode::UNPROCESSABLE_ENTITY,
+        "NOT_PRECOMPUTED" => StatusCode::NOT_FOUND,
+        _ => return None,
+    })
 }
 
+/// Wraps a response in a JSON-RPC 2.0 envelope, echoing the request's `id`.
+/// `request` is `None` if the request body wasn't valid JSON.
+fn jsonrpc_envelope(request: Option<&Value>, resp: Value) -> Value {
+    let id = request
+        .and_then(|request| request.get("id"))
+        .cloned()
+        .unwrap_or(Value::Null);
+    let error = match resp.get("error") {
+        Some(error) => error.clone(),
+        None => {
+            return json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": resp,
+            })
+        }
+    };
+    let code = resp.get("code").and_then(Value::as_str).unwrap_or_default();
+    let jsonrpc_code = match code {
+        "INVALID_JSON" | "EMPTY_BODY" => -32700, // Parse error
+        "UNKNOWN_COMMAND" => -32601, // Method not found
+        "MISSING_FIELD" => -32602, // Invalid params
+        code if code.starts_with("BAD_") => -32602,
+        _ => -32000, // Server error, e.g. cancelled or failed work
+    };
+    let mut data = json!({ "code": code });
+    for field in ["hint", "field", "got"] {
+        if let Some(value) = resp.get(field) {
+            data[field] = value.clone();
+        }
+    }
+    let error = json!({
+        "code": jsonrpc_code,
+        "message": error,
+        "data": data,
+    });
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": error,
+    })
+}
+
+/// Fields that clients written against a Nano node send, which are accepted and ignored.
+/// `use_peers` asks a node to use its work peers, which a work server doesn't have.
+/// Keep these accepted if parsing ever rejects unknown fields.
+const IGNORED_FIELDS: &[&str] = &["use_peers", "account", "version", "block", "json_block"];
+
 enum RpcCommand {
-    WorkGenerate([u8; 32], [u8; 32]),
+    WorkGenerate([u8; 32], [u8; 32], Endian, Option<u64>, bool, Option<Worker>, bool),
     WorkCancel([u8; 32]),
     WorkValidate([u8; 32], [u8; 8], [u8; 32]),
-    Benchmark([u8; 32], u64),
+    WorkPeek([u8; 32], Endian),
+    Benchmark([u8; 32], u64, Option<u64>),
     Status(),
+    ResetStats(),
+    Shutdown(),
+    Version(),
+    QueueDump(bool),
+    ConfigDump(),
+}
+
+/// Byte order of `work` hex strings. The hashed nonce is a little endian u64,
+/// and by default (`Big`) its bytes are reversed so the hex reads as the number.
+#[derive(Clone, Copy)]
+enum Endian {
+    Big,
+    Little,
 }
 
 enum HexJsonError {
@@ -117,39 +1117,317 @@ enum HexJsonError {
 }
 
 impl RpcService {
+    /// A number in a response, as a string like a Nano node unless `--numeric-json`
+    fn number<T: ToString + Into<Value>>(&self, n: T) -> Value {
+        if self.numeric_json {
+            n.into()
+        } else {
+            Value::String(n.to_string())
+        }
+    }
+
     fn generate_work(
         &self,
         root: [u8; 32],
         threshold: [u8; 32],
-    ) -> impl Future<Output = Result<[u8; 8], WorkError>> {
+        client: Option<IpAddr>,
+        allow_partial: bool,
+        worker: Option<Worker>,
+    ) -> PendingWork {
         let mut state = self.work_state.0.lock();
         let (callback_send, callback_recv) = oneshot::channel();
-        state.future_work.push((root, threshold, callback_send));
+        state.enqueue(QueuedWork {
+            root,
+            threshold,
+            callback: callback_send,
+            client,
+            allow_partial,
+            worker,
+            queued_at: Instant::now(),
+        });
+        if state.precomputing {
+            if let Some(callback) = state.callback.take() {
+                let _ = callback.send(Err(WorkError::Superseded));
+            }
+        }
         state.set_task(&self.work_state.1);
-        callback_recv
-            .map_err(|_| WorkError::Errored)
-            .and_then(|x| future::ready(x))
+        PendingWork {
+            result: Some(callback_recv),
+            work_state: self.work_state.clone(),
+            deadline: self.work_timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+        }
     }
 
-    fn cancel_work(&self, root: [u8; 32]) {
+    /// Starts generating work for `--precompute-file` if the workers are idle. The task is
+    /// cancelled as soon as a request comes in.
+    fn precompute_work(
+        &self,
+        root: [u8; 32],
+        threshold: [u8; 32],
+    ) -> Option<PendingWork> {
         let mut state = self.work_state.0.lock();
-        let mut i = 0;
-        while i < state.future_work.len() {
-            if state.future_work[i].0 == root {
-                let (_, _, callback) = state.future_work.remove(i);
-                let _ = callback.send(Err(WorkError::Canceled));
-                continue;
+        if state.callback.is_some() || !state.future_work.is_empty() {
+            return None;
+        }
+        let (callback_send, callback_recv) = oneshot::channel();
+        state.enqueue(QueuedWork {
+            root,
+            threshold,
+            callback: callback_send,
+            client: None,
+            allow_partial: false,
+            worker: None,
+            queued_at: Instant::now(),
+        });
+        state.set_task(&self.work_state.1);
+        state.precomputing = state.callback.is_some();
+        Some(PendingWork {
+            result: Some(callback_recv),
+            work_state: self.work_state.clone(),
+            deadline: None,
+        })
+    }
+
+    /// Runs the roots from `--precompute-file` whenever the workers are idle, caching the work
+    async fn precompute(self, mut roots: mpsc::UnboundedReceiver<precompute::Root>) {
+        while let Some((root, threshold)) = roots.recv().await {
+            loop {
+                let work = match self.precompute_work(root, threshold) {
+                    Some(work) => work,
+                    None => {
+                        tokio::time::sleep(PRECOMPUTE_IDLE_POLL).await;
+                        continue;
+                    }
+                };
+                match work.await {
+                    Ok((work, _)) => {
+                        info!("Precomputed work for {}", hex::encode_upper(root));
+                        self.precomputed.insert(root, work, true);
+                    }
+                    // Gave way to a request, try again once idle
+                    Err(WorkError::Superseded | WorkError::ClientCancel) => continue,
+                    Err(WorkError::Shutdown) => return,
+                    Err(_) => warn!("Failed to precompute work for {}", hex::encode_upper(root)),
+                }
+                break;
             }
-            i += 1;
         }
-        if state.root == root {
-            if let Some(callback) = state.callback.take() {
-                let _ = callback.send(Err(WorkError::Canceled));
-                state.set_task(&self.work_state.1);
+    }
+
+    /// Logs a summary of the queue, the current task and the workers every `period`
+    /// (`--heartbeat-secs`)
+    async fn heartbeat(self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        // The first tick is immediate, and there's nothing to report before any request
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let (queue_size, task) = {
+                let state = self.work_state.0.lock();
+                let task = match (&state.callback, state.task_times) {
+                    (Some(_), Some((_, started_at))) => format!(
+                        "generating {} for {}ms",
+                        hex::encode_upper(state.root),
+                        started_at.elapsed().as_millis()
+                    ),
+                    _ => "idle".to_string(),
+                };
+                (state.future_work.len(), task)
+            };
+            let gpus: Vec<String> = self
+                .gpus
+                .iter()
+                .enumerate()
+                .map(|(i, gpu)| {
+                    format!(
+                        ", GPU {}: {} invalid{}",
+                        i,
+                        gpu.health().invalid_work(),
+                        if gpu.health().disabled() { " (disabled)" } else { "" }
+                    )
+                })
+                .collect();
+            info!(
+                "Heartbeat: {} queued, {}, {:.0} H/s{}",
+                queue_size,
+                task,
+                self.throughput.hashrate(),
+                gpus.concat()
+            );
+        }
+    }
+
+    /// Shuts the server down once no request came in for `after` (`--idle-shutdown-secs`)
+    async fn idle_shutdown(self, after: Duration) {
+        loop {
+            let last_request = *self.activity.last_request.lock();
+            let busy = self.activity.requests.load(atomic::Ordering::Relaxed) > 0;
+            if !busy && last_request.elapsed() >= after {
+                info!("No requests for {}s, shutting down", after.as_secs());
+                self.shutdown.notify_one();
+                return;
             }
+            // A request that is still being answered counts as activity, check again later
+            let wait = after.saturating_sub(last_request.elapsed());
+            tokio::time::sleep(wait.max(Duration::from_secs(1))).await;
         }
     }
 
+    /// Finds work right away for thresholds nearly any nonce meets, skipping the queue and workers
+    fn trivial_work(&self, root: [u8; 32], threshold: [u8; 32]) -> Option<[u8; 8]> {
+        if threshold > difficulty::TRIVIAL_THRESHOLD {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        (0..64)
+            .map(|_| rng.gen())
+            .find(|&work| self.hasher.work_valid(root, work, threshold).0)
+    }
+
+    /// Finds work right away if it's trivial or `precomputed` may be used and there is some,
+    /// or queues it for the workers. Work pinned to a worker is always generated by it.
+    async fn find_work(
+        &self,
+        root: [u8; 32],
+        threshold: [u8; 32],
+        precomputed: bool,
+        client: Option<IpAddr>,
+        allow_partial: bool,
+        worker: Option<Worker>,
+    ) -> Result<([u8; 8], WorkSource), WorkError> {
+        let generated = |(work, solved)| (work, WorkSource::Generated(solved));
+        if worker.is_some() {
+            return self.generate_work(root, threshold, client, allow_partial, worker).await.map(generated);
+        }
+        if let Some(work) = self.trivial_work(root, threshold) {
+            return Ok((work, WorkSource::Trivial));
+        }
+        if precomputed {
+            if let Some(work) = self.cached_work(root, threshold) {
+                info!("Using precomputed work for {}", hex::encode_upper(root));
+                return Ok((work, WorkSource::Stored));
+            }
+        }
+        let local = self.generate_work(root, threshold, client, allow_partial, None);
+        let result = if self.peers.is_empty() {
+            local.await.map(generated)
+        } else {
+            self.fan_out(root, threshold, local).await
+        };
+        match (result, &self.upstream) {
+            (Err(WorkError::Errored), Some(upstream)) => {
+                warn!(
+                    "Failed to generate work for {}, forwarding it to {}",
+                    hex::encode_upper(root),
+                    upstream.uri()
+                );
+                match upstream.work_generate(root, threshold).await {
+                    Ok(work) if self.hasher.work_valid(root, work, threshold).0 => Ok((work, WorkSource::Upstream)),
+                    Ok(work) => {
+                        error!(
+                            "Upstream {} returned invalid work {} for {}",
+                            upstream.uri(),
+                            hex::encode(work),
+                            hex::encode_upper(root)
+                        );
+                        Err(WorkError::Errored)
+                    }
+                    Err(err) => {
+                        error!(
+                            "Upstream {} failed to generate work for {}: {}",
+                            upstream.uri(),
+                            hex::encode_upper(root),
+                            err
+                        );
+                        Err(WorkError::Errored)
+                    }
+                }
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Races `local` against the `--peer` servers and returns the first work found. The others
+    /// are dropped, which cancels the local work and aborts the peer requests. Failed peers
+    /// are only logged, the request fails if the workers and every peer did.
+    async fn fan_out(
+        &self,
+        root: [u8; 32],
+        threshold: [u8; 32],
+        local: PendingWork,
+    ) -> Result<([u8; 8], WorkSource), WorkError> {
+        let peers = future::select_ok(self.peers.iter().enumerate().map(|(i, peer)| {
+            Box::pin(async move {
+                match peer.work_generate(root, threshold).await {
+                    Ok(work) if self.hasher.work_valid(root, work, threshold).0 => Ok((work, WorkSource::Peer(i))),
+                    Ok(work) => Err(format!("Invalid work {}", hex::encode(work))),
+                    Err(err) => Err(err),
+                }
+                .map_err(|err| {
+                    warn!(
+                        "Peer {} failed to generate work for {}: {}",
+                        peer.uri(),
+                        hex::encode_upper(root),
+                        err
+                    )
+                })
+            })
+        }));
+        match future::select(local, peers).await {
+            Either::Left((Ok((work, solved)), _)) => Ok((work, WorkSource::Generated(solved))),
+            // The peers may still come through
+            Either::Left((Err(WorkError::Errored), peers)) => {
+                peers.await.map(|(found, _)| found).map_err(|()| WorkError::Errored)
+            }
+            // Cancelled or out of attempts, which the peers can't change
+            Either::Left((Err(err), _)) => Err(err),
+            Either::Right((Ok((found, _)), _)) => {
+                if let (_, WorkSource::Peer(i)) = found {
+                    info!(
+                        "Peer {} answered first for {}",
+                        self.peers[i].uri(),
+                        hex::encode_upper(root)
+                    );
+                }
+                Ok(found)
+            }
+            Either::Right((Err(()), local)) => local.await.map(|(work, solved)| (work, WorkSource::Generated(solved))),
+        }
+    }
+
+    /// Takes precomputed work for `root` out of the cache, if it meets `threshold`
+    fn cached_work(&self, root: [u8; 32], threshold: [u8; 32]) -> Option<[u8; 8]> {
+        let work = self.precomputed.get(root)?;
+        if !self.hasher.work_valid(root, work, threshold).0 {
+            return None;
+        }
+        self.precomputed.remove(root);
+        Some(work)
+    }
+
+    fn cancel_work(&self, root: [u8; 32]) -> usize {
+        let mut state = self.work_state.0.lock();
+        let mut cancelled = 0;
+        let mut i = 0;
+        while i < state.future_work.len() {
+            if state.future_work[i].root == root {
+                let work = state.future_work.remove(i);
+                let _ = work.callback.send(Err(WorkError::ClientCancel));
+                cancelled += 1;
+                continue;
+            }
+            i += 1;
+        }
+        if state.root == root {
+            if let Some(callback) = state.callback.take() {
+                let _ = callback.send(Err(WorkError::ClientCancel));
+                state.set_task(&self.work_state.1);
+                cancelled += 1;
+            }
+        }
+        cancelled
+    }
+
     fn parse_hex_json(
         value: &Value,
         out: &mut [u8],
@@ -159,7 +1437,7 @@ impl RpcService {
             .as_str()
             .and_then(|s| hex::decode(s).ok())
             .ok_or(HexJsonError::InvalidHex)?;
-        if bytes.len() == 0 {
+        if bytes.is_empty() {
             return Err(HexJsonError::Empty);
         } else if !allow_short && bytes.len() < out.len() {
             return Err(HexJsonError::TooShort);
@@ -173,88 +1451,233 @@ impl RpcService {
     }
 
     fn parse_hash_json(json: &Value) -> Result<[u8; 32], Value> {
-        let root = json.get("hash").ok_or(json!({
+        // Some Vite clients send the hash as "root"
+        let field = if json.get("hash").is_some() { "hash" } else { "root" };
+        let root = json.get(field).ok_or(json!({
             "error": "Failed to deserialize JSON",
-            "hint": "Hash field missing",
+            "code": "MISSING_FIELD",
+            "hint": "Hash field missing (expecting \"hash\" or \"root\")",
         }))?;
         let mut out = [0u8; 32];
-        Self::parse_hex_json(&root, &mut out, false).map_err(|err| match err {
+        Self::parse_hex_json(root, &mut out, false).map_err(|err| match err {
             HexJsonError::Empty => json!({
                 "error": "Bad block hash",
+                "code": "BAD_HASH",
                 "hint": "Hash is empty. Expecting a hex string",
             }),
             HexJsonError::InvalidHex => json!({
                 "error": "Bad block hash",
+                "code": "BAD_HASH",
                 "hint": "Expecting a hex string",
             }),
             HexJsonError::TooShort => json!({
                 "error": "Bad block hash",
+                "code": "BAD_HASH",
                 "hint": "Hash is too short (should be 32 bytes)",
             }),
             HexJsonError::TooLong => json!({
                 "error": "Bad block hash",
+                "code": "BAD_HASH",
                 "hint": "Hash is too long (should be 32 bytes)",
             }),
-        })?;
+        })
+        .map_err(|err| bad_field(err, field, root))?;
         Ok(out)
     }
 
-    fn parse_work_json(json: &Value) -> Result<[u8; 8], Value> {
+    fn parse_work_json(json: &Value, endian: Endian) -> Result<[u8; 8], Value> {
         let root = json.get("work").ok_or(json!({
             "error": "Failed to deserialize JSON",
+            "code": "MISSING_FIELD",
             "hint": "Work field missing",
         }))?;
+        if root.is_number() {
+            // The nonce itself, hashed as a little endian u64 like the hex it would be written as
+            return root.as_u64().map(u64::to_le_bytes).ok_or_else(|| {
+                bad_field(
+                    json!({
+                        "error": "Failed to deserialize JSON",
+                        "code": "BAD_WORK",
+                        "hint": "Expecting an unsigned 64 bit integer for numeric work",
+                    }),
+                    "work",
+                    root,
+                )
+            });
+        }
         let mut out = [0u8; 8];
-        Self::parse_hex_json(&root, &mut out, true).map_err(|err| match err {
+        Self::parse_hex_json(root, &mut out, true).map_err(|err| match err {
             HexJsonError::Empty => json!({
                 "error": "Failed to deserialize JSON",
+                "code": "BAD_WORK",
                 "hint": "Work is empty. Expecting a hex string",
             }),
             HexJsonError::InvalidHex => json!({
                 "error": "Failed to deserialize JSON",
+                "code": "BAD_WORK",
                 "hint": "Expecting a hex string for work",
             }),
             HexJsonError::TooShort => panic!("Unexpected error HexJsonError::TooShort"),
             HexJsonError::TooLong => json!({
                 "error": "Failed to deserialize JSON",
+                "code": "BAD_WORK",
                 "hint": "Work is too long (should be 8 bytes)",
             }),
-        })?;
-        out.reverse();
+        })
+        .map_err(|err| bad_field(err, "work", root))?;
+        // `out` is now the hex as written, with short work right-aligned. The workers hash work
+        // in little endian, so big endian work is reversed here and again when responding.
+        match endian {
+            Endian::Big => out.reverse(),
+            // Short work is padded on the right, i.e. in its most significant bytes
+            Endian::Little => out.rotate_left(8 - root.as_str().map_or(0, |s| s.len() / 2)),
+        }
         Ok(out)
     }
 
+    fn parse_endian_json(&self, json: &Value) -> Result<Endian, Value> {
+        match json.get("endian") {
+            None => Ok(self.default_endian),
+            Some(endian) if endian == "big" => Ok(Endian::Big),
+            Some(endian) if endian == "little" => Ok(Endian::Little),
+            Some(endian) => Err(bad_field(
+                json!({
+                    "error": "Failed to deserialize JSON",
+                    "code": "BAD_ENDIAN",
+                    "hint": "Expecting \"big\" or \"little\" for endian"
+                }),
+                "endian",
+                endian,
+            )),
+        }
+    }
+
     fn parse_threshold_json(json: &Value) -> Result<[u8; 32], Value> {
         let threshold = json.get("threshold").ok_or(json!({
             "error": "Failed to deserialize JSON",
+            "code": "MISSING_FIELD",
             "hint": "Threshold field missing",
         }))?;
         let mut out = [0u8; 32];
-        Self::parse_hex_json(&threshold, &mut out, false).map_err(|err| match err {
+        if let Some(difficulty) = Self::parse_decimal_difficulty(threshold) {
+            out[..8].copy_from_slice(&difficulty.to_be_bytes());
+            return Ok(out);
+        }
+        Self::parse_hex_json(threshold, &mut out, false).map_err(|err| match err {
             HexJsonError::Empty => json!({
                 "error": "Bad threshold",
+                "code": "BAD_THRESHOLD",
                 "hint": "Threshold is empty. Expecting a hex string",
             }),
             HexJsonError::InvalidHex => json!({
                 "error": "Bad threshold",
+                "code": "BAD_THRESHOLD",
                 "hint": "Expecting a hex string",
             }),
             HexJsonError::TooShort => json!({
                 "error": "Bad threshold",
+                "code": "BAD_THRESHOLD",
                 "hint": "Threshold is too short (should be 32 bytes)",
             }),
             HexJsonError::TooLong => json!({
                 "error": "Bad threshold",
+                "code": "BAD_THRESHOLD",
                 "hint": "Threshold is too long (should be 32 bytes)",
             }),
-        })?;
+        })
+        .map_err(|err| bad_field(err, "threshold", threshold))?;
         Ok(out)
     }
 
+    /// A Nano style 8 byte difficulty given as a decimal number, which is the start of a
+    /// threshold. A string of 64 digits is a hex threshold rather, as it would be too large.
+    fn parse_decimal_difficulty(threshold: &Value) -> Option<u64> {
+        match threshold {
+            Value::Number(number) => number.as_u64(),
+            Value::String(s) if s.len() != 64 && !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) => {
+                s.parse().ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_multiplier_json(json: &Value) -> Result<f64, Value> {
+        let multiplier = json.get("multiplier").ok_or(json!({
+            "error": "Failed to deserialize JSON",
+            "code": "MISSING_FIELD",
+            "hint": "Multiplier field missing",
+        }))?;
+        multiplier
+            .as_f64()
+            .or_else(|| multiplier.as_str().and_then(|s| s.parse::<f64>().ok()))
+            .filter(|&x| x.is_finite() && x > 0.0)
+            .ok_or_else(|| {
+                bad_field(
+                    json!({
+                        "error": "Bad multiplier",
+                        "code": "BAD_MULTIPLIER",
+                        "hint": "Expecting a positive number for multiplier",
+                    }),
+                    "multiplier",
+                    multiplier,
+                )
+            })
+    }
+
+    /// Looks up a threshold named with `--difficulty-name`
+    fn parse_difficulty_name_json(&self, name: &Value) -> Result<[u8; 32], Value> {
+        name.as_str()
+            .and_then(|name| self.difficulty_names.get(name))
+            .copied()
+            .ok_or_else(|| {
+                let mut names: Vec<&str> = self.difficulty_names.keys().map(String::as_str).collect();
+                names.sort_unstable();
+                bad_field(
+                    json!({
+                        "error": "Unknown difficulty name",
+                        "code": "BAD_DIFFICULTY_NAME",
+                        "hint": if names.is_empty() {
+                            "No difficulty names are configured (see --difficulty-name)".to_string()
+                        } else {
+                            format!("Expecting one of {} for difficulty_name", names.join(", "))
+                        },
+                    }),
+                    "difficulty_name",
+                    name,
+                )
+            })
+    }
+
+    /// Parses the threshold, or derives it from the multiplier if no threshold is given
+    fn parse_difficulty_json(&self, json: &Value) -> Result<[u8; 32], Value> {
+        if let (None, None, Some(name)) =
+            (json.get("threshold"), json.get("multiplier"), json.get("difficulty_name"))
+        {
+            return self.parse_difficulty_name_json(name);
+        }
+        if json.get("threshold").is_none() && json.get("multiplier").is_some() {
+            let multiplier = Self::parse_multiplier_json(json)?;
+            return Ok(difficulty::from_multiplier(multiplier, self.base_threshold));
+        }
+        Self::parse_threshold_json(json)
+    }
+
+    /// Like `parse_difficulty_json`, but defaults to the base threshold like a Nano node does
+    fn parse_generate_difficulty_json(&self, json: &Value) -> Result<[u8; 32], Value> {
+        if json.get("threshold").is_none()
+            && json.get("multiplier").is_none()
+            && json.get("difficulty_name").is_none()
+        {
+            return Ok(self.base_threshold);
+        }
+        self.parse_difficulty_json(json)
+    }
+
     fn parse_count_json(json: &Value) -> Result<u64, Value> {
         match json.get("count") {
             None => Err(json!({
                 "error": "Failed to deserialize JSON",
+                "code": "MISSING_FIELD",
                 "hint": "count field missing"
             })),
 
@@ -266,139 +1689,571 @@ impl RpcService {
                         .as_str()
                         .and_then(|s| s.parse::<u64>().ok())
                         .filter(|&x| x > 0))
-                    .ok_or(json!({
-                        "error": "Failed to deserialize JSON",
-                        "hint": "Expecting a positive number for count"
-                    }))?;
+                    .ok_or_else(|| {
+                        bad_field(
+                            json!({
+                                "error": "Failed to deserialize JSON",
+                                "code": "BAD_COUNT",
+                                "hint": "Expecting a positive number for count"
+                            }),
+                            "count",
+                            json,
+                        )
+                    })?;
                 Ok(count)
             }
         }
     }
 
-    fn parse_json(&self, json: Value) -> Result<RpcCommand, Value> {
-        match json.get("action") {
-            None => {
-                return Err(json!({
+    /// The optional `count` of `work_generate`, up to `MAX_WORK_COUNT`
+    fn parse_work_count_json(json: &Value) -> Result<Option<u64>, Value> {
+        if json.get("count").is_none() {
+            return Ok(None);
+        }
+        match Self::parse_count_json(json)? {
+            count if count > MAX_WORK_COUNT => Err(bad_field(
+                json!({
                     "error": "Failed to deserialize JSON",
-                    "hint": "Work field missing",
-                }))
+                    "code": "BAD_COUNT",
+                    "hint": format!("count can be at most {} for work_generate", MAX_WORK_COUNT),
+                }),
+                "count",
+                &json["count"],
+            )),
+            count => Ok(Some(count)),
+        }
+    }
+
+    fn parse_seed_json(json: &Value) -> Result<Option<u64>, Value> {
+        match json.get("seed") {
+            None => Ok(None),
+            Some(seed) => seed
+                .as_u64()
+                .or_else(|| seed.as_str().and_then(|s| s.parse::<u64>().ok()))
+                .map(Some)
+                .ok_or_else(|| {
+                    bad_field(
+                        json!({
+                            "error": "Failed to deserialize JSON",
+                            "code": "BAD_SEED",
+                            "hint": "Expecting a non-negative integer for seed"
+                        }),
+                        "seed",
+                        seed,
+                    )
+                }),
+        }
+    }
+
+    fn parse_worker_json(&self, json: &Value) -> Result<Option<Worker>, Value> {
+        let value = match json.get("worker") {
+            None => return Ok(None),
+            Some(value) => value,
+        };
+        let worker = value.as_str().and_then(|s| s.split_once(':')).and_then(|(kind, i)| {
+            let i = i.parse().ok()?;
+            match kind {
+                "cpu" if i < self.cpu_threads => Some(Worker::Cpu(i)),
+                "gpu" if i < self.gpus.len() => Some(Worker::Gpu(i)),
+                _ => None,
             }
+        });
+        worker.map(Some).ok_or_else(|| {
+            bad_field(
+                json!({
+                    "error": "Failed to deserialize JSON",
+                    "code": "BAD_WORKER",
+                    "hint": format!(
+                        "Expecting gpu:N or cpu:N for worker, counting from 0, with {} GPUs and {} CPU threads",
+                        self.gpus.len(),
+                        self.cpu_threads
+                    ),
+                }),
+                "worker",
+                value,
+            )
+        })
+    }
+
+    fn parse_json(&self, json: &Value) -> Result<RpcCommand, Value> {
+        if let Some(action) = json.get("action").and_then(Value::as_str) {
+            if self.disabled_actions.contains(&action) {
+                return Err(json!({
+                    "error": "Action disabled",
+                    "code": "DISABLED",
+                    "hint": format!("{} is disabled on this server (--disable-action)", action),
+                }));
+            }
+        }
+        match json.get("action") {
+            None => Err(json!({
+                "error": "Failed to deserialize JSON",
+                "code": "MISSING_FIELD",
+                "hint": "Action field missing",
+            })),
             Some(action) if action == "work_generate" => Ok(RpcCommand::WorkGenerate(
-                Self::parse_hash_json(&json)?,
-                Self::parse_threshold_json(&json)?
+                Self::parse_hash_json(json)?,
+                self.parse_generate_difficulty_json(json)?,
+                self.parse_endian_json(json)?,
+                Self::parse_work_count_json(json)?,
+                json.get("allow_partial").and_then(Value::as_bool).unwrap_or(false),
+                self.parse_worker_json(json)?,
+                json.get("verbose").and_then(Value::as_bool).unwrap_or(false),
             )),
             Some(action) if action == "work_cancel" => {
-                Ok(RpcCommand::WorkCancel(Self::parse_hash_json(&json)?))
+                Ok(RpcCommand::WorkCancel(Self::parse_hash_json(json)?))
             }
             Some(action) if action == "work_validate" => Ok(RpcCommand::WorkValidate(
-                Self::parse_hash_json(&json)?,
-                Self::parse_work_json(&json)?,
-                Self::parse_threshold_json(&json)?
+                Self::parse_hash_json(json)?,
+                Self::parse_work_json(json, self.parse_endian_json(json)?)?,
+                self.parse_difficulty_json(json)?
+            )),
+            Some(action) if action == "work_peek" => Ok(RpcCommand::WorkPeek(
+                Self::parse_hash_json(json)?,
+                self.parse_endian_json(json)?,
             )),
             Some(action) if action == "benchmark" => Ok(RpcCommand::Benchmark(
-                Self::parse_threshold_json(&json)?,
-                Self::parse_count_json(&json)?,
+                self.parse_difficulty_json(json)?,
+                Self::parse_count_json(json)?,
+                Self::parse_seed_json(json)?,
             )),
             Some(action) if action == "status" => Ok(RpcCommand::Status()),
-            Some(_) => {
-                return Err(json!({
-                    "error": "Unknown command",
-                    "hint": "Supported commands: work_generate, work_cancel, work_validate, benchmark, status"
-                }))
+            Some(action) if action == "reset_stats" => Ok(RpcCommand::ResetStats()),
+            Some(action) if action == "version" => Ok(RpcCommand::Version()),
+            Some(action) if action == "queue_dump" => {
+                if self.api_key.is_some() {
+                    Ok(RpcCommand::QueueDump(
+                        json.get("include_clients").and_then(Value::as_bool).unwrap_or(false),
+                    ))
+                } else {
+                    Err(json!({
+                        "error": "Queue dump disabled",
+                        "code": "QUEUE_DUMP_DISABLED",
+                        "hint": "Start the server with --api-key to enable it",
+                    }))
+                }
+            }
+            Some(action) if action == "config_dump" => {
+                if self.api_key.is_some() {
+                    Ok(RpcCommand::ConfigDump())
+                } else {
+                    Err(json!({
+                        "error": "Config dump disabled",
+                        "code": "CONFIG_DUMP_DISABLED",
+                        "hint": "Start the server with --api-key to enable it",
+                    }))
+                }
+            }
+            Some(action) if action == "shutdown" => {
+                if self.allow_remote_shutdown {
+                    Ok(RpcCommand::Shutdown())
+                } else {
+                    Err(json!({
+                        "error": "Remote shutdown disabled",
+                        "code": "SHUTDOWN_DISABLED",
+                        "hint": "Start the server with --api-key and --allow-remote-shutdown to enable it",
+                    }))
+                }
             }
+            Some(_) => Err(json!({
+                "error": "Unknown command",
+                "code": "UNKNOWN_COMMAND",
+                "hint": "Supported commands: work_generate, work_cancel, work_validate, work_peek, benchmark, status, reset_stats, shutdown, version, queue_dump, config_dump"
+            })),
         }
     }
 
-    async fn process_req(self, body: &[u8]) -> hyper::Result<(StatusCode, Value)> {
-        let json = match serde_json::from_slice(body) {
-            Ok(json) => json,
-            Err(_) => {
+    /// Answers a request to the RPC path. `request` is its parsed `body`, `None` if that isn't
+    /// valid JSON.
+    async fn process_req(
+        self,
+        body: &[u8],
+        request: Option<&Value>,
+        client: SocketAddr,
+        authorized: bool,
+    ) -> hyper::Result<(StatusCode, Value)> {
+        // Every request counts towards the rate, but only work generation is shed
+        let overloaded = self.request_rate.as_ref().is_some_and(|rate| rate.hit());
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                json!({
+                    "error": "Empty request body",
+                    "code": "EMPTY_BODY",
+                    "hint": "Expecting a JSON object with an \"action\"",
+                }),
+            ));
+        }
+        let json = match request {
+            Some(json) => json,
+            None => {
                 return Ok((
                     StatusCode::BAD_REQUEST,
                     json!({
                         "error": "Failed to deserialize JSON",
+                        "code": "INVALID_JSON",
                     }),
                 ));
             }
         };
+        let ignored: Vec<&str> = IGNORED_FIELDS
+            .iter()
+            .copied()
+            .filter(|&field| json.get(field).is_some())
+            .collect();
+        if !ignored.is_empty() {
+            info!("Ignoring {} from {}", ignored.join(", "), client);
+        }
+        // Only reached without the key with --auth-actions, which leaves some actions open
+        if !authorized && json.get("action").and_then(Value::as_str).is_some_and(|action| self.needs_key(action)) {
+            return Ok(Self::unauthorized(client));
+        }
         let command = match self.parse_json(json) {
             Ok(r) => r,
             Err(err) => return Ok((StatusCode::BAD_REQUEST, err)),
         };
         let start = Instant::now();
         match command {
-            RpcCommand::WorkGenerate(root, threshold) => {
+            RpcCommand::WorkGenerate(root, threshold, endian, count, allow_partial, worker, verbose) => {
+                if overloaded {
+                    info!("Shedding work for {} from {}", hex::encode_upper(root), client);
+                    return Ok((
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        json!({
+                            "error": "Overloaded",
+                            "code": "OVERLOADED",
+                            "hint": format!(
+                                "More than {} requests per second are coming in (see --max-request-rate-global), retry later",
+                                self.request_rate.as_ref().map_or(0, |rate| rate.limit())
+                            ),
+                        }),
+                    ));
+                }
+                if let Some(max_threshold) = self.max_threshold.filter(|&max| threshold > max) {
+                    info!(
+                        "Rejecting work for {} from {} at threshold {}",
+                        hex::encode_upper(root),
+                        client,
+                        hex::encode(threshold)
+                    );
+                    return Ok((
+                        StatusCode::BAD_REQUEST,
+                        json!({
+                            "error": "Threshold too high",
+                            "code": "THRESHOLD_TOO_HIGH",
+                            "hint": format!(
+                                "Multiplier {} is above the maximum of {} (see --max-generation-multiplier)",
+                                difficulty::to_multiplier(threshold, self.base_threshold),
+                                difficulty::to_multiplier(max_threshold, self.base_threshold)
+                            ),
+                        }),
+                    ));
+                }
+                if let Some(worker) = worker {
+                    let disabled = match worker {
+                        Worker::Gpu(i) => self.gpus[i].health().disabled(),
+                        Worker::Cpu(_) => false,
+                    };
+                    if disabled || self.work_state.0.lock().dead.contains(&worker) {
+                        return Ok((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            json!({
+                                "error": "Worker unavailable",
+                                "code": "WORKER_UNAVAILABLE",
+                                "hint": format!("{} crashed or was disabled, see status", worker),
+                            }),
+                        ));
+                    }
+                }
+                if allow_partial {
+                    let state = self.work_state.0.lock();
+                    let cpu_runs = match worker {
+                        Some(worker) => matches!(worker, Worker::Cpu(_)),
+                        None => (0..self.cpu_threads).any(|i| !state.dead.contains(&Worker::Cpu(i))),
+                    };
+                    if !cpu_runs {
+                        return Ok((
+                            StatusCode::BAD_REQUEST,
+                            json!({
+                                "error": "Partial work unsupported",
+                                "code": "PARTIAL_UNSUPPORTED",
+                                "hint": "Only CPU threads keep track of their best work for allow_partial, and none would run this request (see --cpu-threads)",
+                            }),
+                        ));
+                    }
+                }
                 let now: DateTime<Utc> = Utc::now();
-                let _ = println!(
-                    "{} Received work for {}",
+                info!(
+                    "{} Received work for {} from {}{}",
                     now.format("%T"),
-                    hex::encode_upper(&root)
+                    hex::encode_upper(root),
+                    client,
+                    worker.map(|worker| format!(" for {}", worker)).unwrap_or_default()
                 );
-                match self.generate_work(root, threshold).await {
-                    Ok(mut work) => {
-                        let result_threshold = work_value(root, work);
+                self.stats.lock().work_requests += 1;
+                let threshold = match self.min_threshold {
+                    Some(min_threshold) if threshold < min_threshold => {
+                        info!(
+                            "Raising threshold {} for {} to the minimum {}",
+                            hex::encode(threshold),
+                            hex::encode_upper(root),
+                            hex::encode(min_threshold)
+                        );
+                        min_threshold
+                    }
+                    _ => threshold,
+                };
+                let mut works = Vec::new();
+                let mut source = None;
+                let mut result = Ok(());
+                let mut incomplete = false;
+                // Each work is searched for from a random nonce, so repeats are very unlikely
+                // One at a time, so a client that goes away cancels the one in progress and
+                // leaves nothing else queued
+                while works.len() < count.unwrap_or(1) as usize {
+                    let first = works.is_empty();
+                    match self.find_work(root, threshold, first, Some(client.ip()), allow_partial, worker).await {
+                        Ok((work, _)) if works.contains(&work) => {}
+                        Ok((work, work_source)) => {
+                            source.get_or_insert(work_source);
+                            works.push(work);
+                        }
+                        Err(WorkError::ExhaustedAttempts(Some(best))) => {
+                            works.push(best);
+                            incomplete = true;
+                            break;
+                        }
+                        Err(err) => {
+                            result = Err(err);
+                            break;
+                        }
+                    }
+                }
+                if let Some(slow_request) = self.slow_request {
+                    if start.elapsed() > slow_request {
+                        warn!(
+                            "Slow request: work for {} at threshold {} from {} took {}ms",
+                            hex::encode_upper(root),
+                            hex::encode(threshold),
+                            client,
+                            start.elapsed().as_millis()
+                        );
+                    }
+                }
+                match result.map(|()| works) {
+                    Ok(works) => {
+                        let encode = |mut work: [u8; 8]| {
+                            let result_threshold = self.hasher.work_value(root, work);
+                            if let Endian::Big = endian {
+                                work.reverse();
+                            }
+                            (hex::encode(work), hex::encode(result_threshold))
+                        };
+                        let (work, result_threshold) = encode(works[0]);
+                        if incomplete {
+                            self.stats.lock().work_failed += 1;
+                            info!(
+                                "Attempts exhausted for {}, returning the best work found",
+                                hex::encode_upper(root)
+                            );
+                        } else {
+                            // For work_peek, or a request for the same root
+                            self.precomputed.insert(root, works[0], false);
+                            let mut stats = self.stats.lock();
+                            stats.work_generated += 1;
+                            stats.record_duration(start.elapsed().as_millis());
+                        }
                         let now: DateTime<Utc> = Utc::now();
-                        let _ = println!(
+                        info!(
                             "{} Generated for {} in {}ms for threshold {}",
                             now.format("%T"),
-                            hex::encode_upper(&root),
+                            hex::encode_upper(root),
                             start.elapsed().as_millis(),
-                            hex::encode(&result_threshold)
+                            result_threshold
                         );
-                        // Reverse before encoding
-                        work.reverse();
+                        if let (Some(webhook), false) = (&self.webhook, incomplete) {
+                            webhook.notify(json!({
+                                "hash": hex::encode_upper(root),
+                                "work": work,
+                                "threshold": result_threshold,
+                            }));
+                        }
+                        let mut resp = json!({
+                            "work": work,
+                            "threshold": result_threshold,
+                            "target_threshold": hex::encode(threshold),
+                        });
+                        if count.is_some() {
+                            resp["works"] = works
+                                .into_iter()
+                                .map(encode)
+                                .map(|(work, threshold)| json!({ "work": work, "threshold": threshold }))
+                                .collect();
+                        }
+                        if incomplete {
+                            resp["incomplete"] = json!(true);
+                        }
+                        if verbose {
+                            let solved = match source {
+                                Some(WorkSource::Generated(solved)) => Some(solved),
+                                _ => None,
+                            };
+                            resp["solved_by"] = match source {
+                                Some(WorkSource::Trivial) => json!("trivial"),
+                                Some(WorkSource::Stored) => json!("stored"),
+                                Some(WorkSource::Upstream) => json!("upstream"),
+                                Some(WorkSource::Peer(i)) => json!(format!("peer:{}", i)),
+                                Some(WorkSource::Generated(solved)) => json!(solved.worker.to_string()),
+                                None => Value::Null,
+                            };
+                            resp["attempts"] = json!(solved.map(|solved| self.number(solved.attempts)));
+                            resp["queued"] =
+                                json!(solved.map(|solved| self.number(solved.queued.as_millis() as u64)));
+                            resp["computing"] =
+                                json!(solved.map(|solved| self.number(solved.computing.as_millis() as u64)));
+                        }
+                        Ok((StatusCode::OK, resp))
+                    }
+                    Err(WorkError::ClientCancel) => {
+                        self.stats.lock().work_cancelled += 1;
                         Ok((
                             StatusCode::OK,
                             json!({
-                                "work": hex::encode(&work),
-                                "threshold": hex::encode(result_threshold)
+                                "error": "Cancelled",
+                                "code": "CANCELLED",
+                                "hint": "Cancelled by work_cancel",
+                            }),
+                        ))
+                    }
+                    Err(WorkError::Superseded) => {
+                        self.stats.lock().work_cancelled += 1;
+                        Ok((
+                            StatusCode::OK,
+                            json!({
+                                "error": "Cancelled",
+                                "code": "SUPERSEDED",
+                                "hint": "Gave way to another request",
+                            }),
+                        ))
+                    }
+                    Err(WorkError::Shutdown) => {
+                        self.stats.lock().work_cancelled += 1;
+                        Ok((
+                            StatusCode::OK,
+                            json!({
+                                "error": "Cancelled",
+                                "code": "SHUTTING_DOWN",
+                                "hint": "The server is shutting down, retry with another one",
+                            }),
+                        ))
+                    }
+                    Err(WorkError::Timeout) => {
+                        self.stats.lock().work_cancelled += 1;
+                        Ok((
+                            StatusCode::OK,
+                            json!({
+                                "error": "Cancelled",
+                                "code": "WORK_TIMEOUT",
+                                "hint": format!(
+                                    "Not generated within {}s (see --work-timeout), retry later or with another server",
+                                    self.work_timeout.map_or(0, |timeout| timeout.as_secs())
+                                ),
+                            }),
+                        ))
+                    }
+                    Err(WorkError::Errored) => {
+                        self.stats.lock().work_failed += 1;
+                        Ok((
+                            StatusCode::OK,
+                            json!({
+                                "error": "Work generation failed (see logs for details)",
+                                "code": "GENERATION_FAILED",
+                            }),
+                        ))
+                    }
+                    Err(WorkError::ExhaustedAttempts(_)) => {
+                        self.stats.lock().work_failed += 1;
+                        Ok((
+                            StatusCode::OK,
+                            json!({
+                                "error": "ExhaustedAttempts",
+                                "code": "EXHAUSTED_ATTEMPTS",
                             }),
                         ))
                     }
-                    Err(WorkError::Canceled) => Ok((
-                        StatusCode::OK,
-                        json!({
-                            "error": "Cancelled",
-                        }),
-                    )),
-                    Err(WorkError::Errored) => Ok((
-                        StatusCode::OK,
-                        json!({
-                            "error": "Work generation failed (see logs for details)",
-                        }),
-                    )),
                 }
             }
             RpcCommand::WorkCancel(root) => {
-                let _ = println!("Cancel {}", hex::encode_upper(&root));
-                self.cancel_work(root);
-                Ok((StatusCode::OK, json!({})))
+                let cancelled = self.cancel_work(root);
+                info!("Cancel {} from {}, {} cancelled", hex::encode_upper(root), client, cancelled);
+                Ok((StatusCode::OK, json!({ "cancelled": self.number(cancelled) })))
             }
             RpcCommand::WorkValidate(root, work, threshold) => {
-                let _ = println!("Validate {}", hex::encode_upper(&root));
-                let (valid, result_threshold) = work_valid(root, work, threshold);
+                info!("Validate {} from {}", hex::encode_upper(root), client);
+                let (valid, result_threshold) = self.hasher.work_valid(root, work, threshold);
+                let multiplier = difficulty::to_multiplier(result_threshold, self.base_threshold);
                 let result = json!({
                     "valid": valid,
-                    "threshold": hex::encode(result_threshold)
+                    "threshold": hex::encode(result_threshold),
+                    "multiplier": self.number(multiplier),
                 });
                 Ok((StatusCode::OK, result))
             }
-            RpcCommand::Benchmark(threshold, count) => {
-                let _ = println!(
-                    "Benchmarking {} samples at threshold {}",
-                    count, hex::encode(threshold),
-                );
-                let mut roots: Vec<[u8; 32]> = Vec::new();
-                roots.reserve(count as usize);
-                for _ in 0..count {
-                    roots.push(rand::random())
+            RpcCommand::WorkPeek(root, endian) => {
+                info!("Peek {} from {}", hex::encode_upper(root), client);
+                let mut work = match self.precomputed.get(root) {
+                    Some(work) => work,
+                    None => {
+                        return Ok((
+                            StatusCode::OK,
+                            json!({
+                                "error": "Work not precomputed",
+                                "code": "NOT_PRECOMPUTED",
+                                "hint": "Only work precomputed from --precompute-file or generated recently is stored",
+                            }),
+                        ))
+                    }
+                };
+                let result_threshold = self.hasher.work_value(root, work);
+                if let Endian::Big = endian {
+                    work.reverse();
+                }
+                Ok((
+                    StatusCode::OK,
+                    json!({
+                        "work": hex::encode(work),
+                        "threshold": hex::encode(result_threshold),
+                        "multiplier": self.number(difficulty::to_multiplier(result_threshold, self.base_threshold)),
+                    }),
+                ))
+            }
+            RpcCommand::Benchmark(threshold, count, seed) => {
+                if count > self.max_benchmark_count {
+                    return Ok((
+                        StatusCode::BAD_REQUEST,
+                        json!({
+                            "error": "Failed to deserialize JSON",
+                            "code": "BAD_COUNT",
+                            "hint": format!("count can be at most {} (see --max-benchmark-count)", self.max_benchmark_count),
+                        }),
+                    ));
                 }
+                let multiplier = difficulty::to_multiplier(threshold, self.base_threshold);
+                info!(
+                    "Benchmarking {} samples at threshold {} (multiplier {:.4}) for {}",
+                    count, hex::encode(threshold), multiplier, client,
+                );
+                // Runs with the same seed work on the same roots
+                let seed = seed.unwrap_or_else(rand::random);
+                let mut seed_rng = XorShiftRng::seed_from_u64(seed);
                 let start = Instant::now();
-                for root in roots {
-                    if self.generate_work(root, threshold).await.is_err() {
+                for _ in 0..count {
+                    let root = seed_rng.gen();
+                    if self.generate_work(root, threshold, Some(client.ip()), false, None).await.is_err() {
                         return Ok((StatusCode::INTERNAL_SERVER_ERROR, {
                             json!({
                                 "error": "Benchmark failed",
+                                "code": "BENCHMARK_FAILED",
                                 "hint": "Work generation failure",
                             })
                         }));
@@ -406,58 +2261,851 @@ impl RpcService {
                 }
                 let duration = start.elapsed().as_millis();
                 let average = duration as u64 / count;
-                println!(
-                    "Benchmark finished in {}ms , average {}ms / sample",
-                    duration, average
+                info!(
+                    "Benchmark finished in {}ms , average {}ms / sample at multiplier {:.4}",
+                    duration, average, multiplier
                 );
                 Ok((StatusCode::OK, {
                     json!({
                         "threshold": hex::encode(threshold),
-                        "count": format!("{}", count),
-                        "duration": format!("{}", duration),
-                        "average": format!("{}", average),
+                        "multiplier": self.number(multiplier),
+                        "seed": self.number(seed),
+                        "count": self.number(count),
+                        "duration": self.number(duration as u64),
+                        "average": self.number(average),
                         "hint": "Times in milliseconds",
                     })
                 }))
             }
             RpcCommand::Status() => {
                 let state = self.work_state.0.lock();
+                let stats = self.stats.lock();
                 let queue_size = state.future_work.len();
+                let mut queue_multipliers = [0u64; 3];
+                for work in &state.future_work {
+                    let multiplier = difficulty::to_multiplier(work.threshold, self.base_threshold);
+                    let bucket = match multiplier {
+                        m if m < 1.0 => 0,
+                        m if m <= 2.0 => 1,
+                        _ => 2,
+                    };
+                    queue_multipliers[bucket] += 1;
+                }
+                let (stored_results, precomputed) = self.precomputed.len();
+                let live_workers = state.n_workers.saturating_sub(state.dead_workers);
+                let idle_workers = match (state.callback.is_some(), state.task_worker.or(state.task_assignee)) {
+                    (false, _) => live_workers,
+                    // Everyone but the pinned or assigned worker, unless it gave up too
+                    (true, Some(_)) if state.unsuccessful_workers == 0 => live_workers.saturating_sub(1),
+                    (true, Some(_)) => live_workers,
+                    (true, None) => state.unsuccessful_workers.min(live_workers),
+                };
                 let resp = json!({
-                    "queue_size": format!("{}", queue_size),
-                    "generating": if state.task_complete.load(atomic::Ordering::Relaxed) {"0"} else {"1"},
+                    "queue_size": self.number(queue_size),
+                    "current_best_multiplier": state.best.filter(|_| state.callback.is_some()).map(|(_, value)| {
+                        self.number(difficulty::to_multiplier(value, self.base_threshold))
+                    }),
+                    "queue_multipliers": {
+                        "<1x": self.number(queue_multipliers[0]),
+                        "1-2x": self.number(queue_multipliers[1]),
+                        ">2x": self.number(queue_multipliers[2]),
+                    },
+                    // The callback is only set while a task runs, unlike `task_complete` which
+                    // lags behind between a task ending and the next one starting
+                    "generating": self.number(state.callback.is_some() as u64),
+                    "active_tasks": self.number(state.callback.is_some() as u64),
+                    "idle_workers": self.number(idle_workers),
+                    "connections": self.number(self.connections.load(atomic::Ordering::Relaxed)),
+                    "stats_since": stats.since.to_rfc3339(),
+                    "work_requests": self.number(stats.work_requests),
+                    "work_generated": self.number(stats.work_generated),
+                    "work_cancelled": self.number(stats.work_cancelled),
+                    "work_failed": self.number(stats.work_failed),
+                    "average_duration": stats.average_duration().map(|d| self.number(d as u64)),
+                    "hashrate": self.number(self.throughput.hashrate().round() as u64),
+                    "precomputed": self.number(precomputed),
+                    "stored_results": self.number(stored_results),
+                    "gpus": self.gpus.iter().map(|gpu| {
+                        let stats = gpu.stats();
+                        json!({
+                            "disabled": gpu.health().disabled(),
+                            "valid_work": self.number(gpu.health().valid_work()),
+                            "invalid_work": self.number(gpu.health().invalid_work()),
+                            "hashrate": self.number(gpu.health().throughput().hashrate().round() as u64),
+                            "name": stats.name,
+                            "memory_total": stats.memory_total.map(|x| self.number(x)),
+                            "memory_free": stats.memory_free.map(|x| self.number(x)),
+                        })
+                    }).collect::<Vec<_>>(),
                 });
-                println!("Status {}", resp);
+                info!("Status for {} {}", client, resp);
                 Ok((StatusCode::OK, resp))
             }
+            RpcCommand::ResetStats() => {
+                let mut stats = self.stats.lock();
+                *stats = Stats::new();
+                info!("Statistics reset by {}", client);
+                Ok((
+                    StatusCode::OK,
+                    json!({
+                        "reset_at": stats.since.to_rfc3339(),
+                    }),
+                ))
+            }
+            RpcCommand::Version() => Ok((StatusCode::OK, version_info())),
+            RpcCommand::ConfigDump() => {
+                info!("Config dump for {}", client);
+                Ok((StatusCode::OK, (*self.config).clone()))
+            }
+            RpcCommand::QueueDump(include_clients) => {
+                info!("Queue dump for {}", client);
+                let state = self.work_state.0.lock();
+                let now = Instant::now();
+                let entry = |root: [u8; 32], threshold: [u8; 32], queued_at: Instant, client: Option<IpAddr>| {
+                    let mut entry = json!({
+                        "hash": hex::encode(root),
+                        "threshold": hex::encode(threshold),
+                        "waiting": self.number(now.duration_since(queued_at).as_millis() as u64),
+                    });
+                    if include_clients {
+                        entry["client"] = json!(client.map(|client| client.to_string()));
+                    }
+                    entry
+                };
+                let active = match (&state.callback, state.task_times) {
+                    (Some(_), Some((queued_at, started_at))) => {
+                        let mut active = entry(state.root, state.threshold, queued_at, state.task_client);
+                        active["running"] = self.number(now.duration_since(started_at).as_millis() as u64);
+                        active["precomputing"] = json!(state.precomputing);
+                        active
+                    }
+                    _ => Value::Null,
+                };
+                let queue: Vec<Value> = state
+                    .future_work
+                    .iter()
+                    .map(|work| entry(work.root, work.threshold, work.queued_at, work.client))
+                    .collect();
+                Ok((StatusCode::OK, json!({ "active": active, "queue": queue })))
+            }
+            RpcCommand::Shutdown() => {
+                info!("Shutdown requested by {}", client);
+                // The server waits for this response to be sent before stopping
+                self.shutdown.notify_one();
+                Ok((
+                    StatusCode::OK,
+                    json!({
+                        "status": "shutting down",
+                    }),
+                ))
+            }
         }
     }
 
-    async fn handle_request(self, mut req: Request<Body>) -> hyper::Result<Response<Body>> {
-        let (status, body) = if *req.method() == hyper::Method::POST {
-            let self_copy = self.clone();
-            let body = hyper::body::to_bytes(req.body_mut()).await?;
-            self_copy.process_req(body.as_ref()).await?
-        } else {
-            (
-                StatusCode::METHOD_NOT_ALLOWED,
-                json!({
-                    "error": "Can only POST requests",
-                }),
-            )
+    /// Whether `action` is only for clients with the API key. `queue_dump`, `config_dump` and
+    /// `shutdown` always are, as they're only enabled with one.
+    fn needs_key(&self, action: &str) -> bool {
+        matches!(action, "queue_dump" | "config_dump" | "shutdown")
+            || self.auth_actions.as_ref().is_none_or(|actions| actions.contains(&action))
+    }
+
+    fn unauthorized(client: SocketAddr) -> (StatusCode, Value) {
+        info!("Rejected unauthorized request from {}", client);
+        (
+            StatusCode::UNAUTHORIZED,
+            json!({
+                "error": "Unauthorized",
+                "code": "UNAUTHORIZED",
+                "hint": "Expecting an \"Authorization: Bearer <api key>\" header",
+            }),
+        )
+    }
+
+    fn authorized(&self, req: &Request<Body>) -> bool {
+        let api_key = match &self.api_key {
+            Some(api_key) => api_key,
+            None => return true,
+        };
+        req.headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|key| constant_time_eq(key.as_bytes(), api_key.as_bytes()))
+    }
+
+    /// Whether the request is declared JSON, or lacking `--strict-content-type` any body is taken as JSON
+    fn json_content_type(&self, req: &Request<Body>) -> bool {
+        if !self.strict_content_type {
+            return true;
+        }
+        req.headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+    }
+
+    /// Generates work for random roots for `STARTUP_BENCH_TIME` and logs how fast each worker went
+    async fn startup_bench(&self) {
+        info!(
+            "Benchmarking the workers for {}s (--startup-bench)",
+            STARTUP_BENCH_TIME.as_secs()
+        );
+        let gpu_totals = || -> Vec<u64> {
+            self.gpus.iter().map(|gpu| gpu.health().throughput().total()).collect()
+        };
+        let total_before = self.throughput.total();
+        let gpus_before = gpu_totals();
+        let start = Instant::now();
+        let mut works = 0;
+        while let Some(left) = STARTUP_BENCH_TIME.checked_sub(start.elapsed()) {
+            let root = rand::random();
+            let work = self.generate_work(root, self.base_threshold, None, false, None);
+            match tokio::time::timeout(left, work).await {
+                Ok(Ok(_)) => works += 1,
+                Ok(Err(_)) => {
+                    error!("Work generation failed, stopping the benchmark");
+                    return;
+                }
+                Err(_) => {
+                    self.cancel_work(root);
+                }
+            }
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        let total = (self.throughput.total() - total_before) as f64 / elapsed;
+        // The chance of a nonce meeting the base threshold, from its first 8 bytes
+        let base = u64::from_be_bytes(self.base_threshold[..8].try_into().unwrap());
+        let chance = (u64::MAX - base) as f64 / u64::MAX as f64;
+        info!(
+            "Benchmark: {:.0} hashes/s, an estimated {:.3} works/s at the base threshold ({} found)",
+            total,
+            total * chance,
+            works
+        );
+        let share = |rate: f64| if total > 0.0 { rate / total * 100.0 } else { 0.0 };
+        let mut gpus_rate = 0.0;
+        for (i, (gpu, before)) in self.gpus.iter().zip(gpus_before).enumerate() {
+            let rate = (gpu.health().throughput().total() - before) as f64 / elapsed;
+            gpus_rate += rate;
+            info!(
+                "Benchmark: GPU {} ({}) {:.0} hashes/s ({:.1}%)",
+                i,
+                gpu.stats().name.unwrap_or_else(|| "unknown".to_string()),
+                rate,
+                share(rate)
+            );
+        }
+        let cpu_rate = total - gpus_rate;
+        if cpu_rate > 0.0 {
+            info!(
+                "Benchmark: CPU threads {:.0} hashes/s ({:.1}%)",
+                cpu_rate,
+                share(cpu_rate)
+            );
+        }
+    }
+
+    /// Checks the hashing against a known answer, then generates work for random roots and
+    /// verifies it. GPUs returning invalid work fail the test even though the work they
+    /// return is verified before being used.
+    async fn self_test(&self) -> bool {
+        let mut passed = true;
+        if self.hasher.length() == 32 && self.hasher.key().is_empty() && self.hasher.personal().is_empty() {
+            let value = self.hasher.work_value(SELF_TEST_KNOWN_ROOT, SELF_TEST_KNOWN_WORK);
+            if value == SELF_TEST_KNOWN_VALUE {
+                info!("Known answer: pass");
+            } else {
+                error!(
+                    "Known answer: FAIL, got {} instead of {}",
+                    hex::encode(value),
+                    hex::encode(SELF_TEST_KNOWN_VALUE)
+                );
+                passed = false;
+            }
+        }
+        let invalid_before: Vec<u64> = self.gpus.iter().map(|gpu| gpu.health().invalid_work()).collect();
+        let valid_before: Vec<u64> = self.gpus.iter().map(|gpu| gpu.health().valid_work()).collect();
+        let mut verified = 0;
+        for _ in 0..SELF_TEST_ROOTS {
+            let root: [u8; 32] = rand::random();
+            let work = tokio::time::timeout(
+                SELF_TEST_TIMEOUT,
+                self.generate_work(root, SELF_TEST_THRESHOLD, None, false, None),
+            );
+            match work.await {
+                Ok(Ok((work, _))) if self.hasher.work_valid(root, work, SELF_TEST_THRESHOLD).0 => verified += 1,
+                Ok(Ok((work, _))) => error!(
+                    "Work {} for root {} doesn't meet the threshold",
+                    hex::encode(work),
+                    hex::encode_upper(root)
+                ),
+                Ok(Err(_)) => error!("Failed to generate work for root {}", hex::encode_upper(root)),
+                Err(_) => {
+                    error!("Timed out generating work for root {}", hex::encode_upper(root));
+                    self.cancel_work(root);
+                }
+            }
+        }
+        if verified < SELF_TEST_ROOTS {
+            passed = false;
+        }
+        info!(
+            "Work: {}/{} verified{}",
+            verified,
+            SELF_TEST_ROOTS,
+            if verified == SELF_TEST_ROOTS { "" } else { ", FAIL" }
+        );
+        for (i, gpu) in self.gpus.iter().enumerate() {
+            let valid = gpu.health().valid_work() - valid_before[i];
+            let invalid = gpu.health().invalid_work() - invalid_before[i];
+            let result = if invalid > 0 {
+                passed = false;
+                "FAIL"
+            } else if valid == 0 {
+                "no results, CPU threads may have been faster"
+            } else {
+                "pass"
+            };
+            info!("GPU {}: {} valid, {} invalid results: {}", i, valid, invalid, result);
+        }
+        info!("Self test {}", if passed { "passed" } else { "FAILED" });
+        passed
+    }
+
+    /// Handles a request, recording its action, status and latency for `/metrics`
+    /// `in_flight` counts the requests on the client's connection, including this one
+    async fn serve(
+        self,
+        req: Request<Body>,
+        client: SocketAddr,
+        in_flight: usize,
+    ) -> hyper::Result<Response<Body>> {
+        let start = Instant::now();
+        let metrics = self.metrics.clone();
+        let activity = self.activity.clone();
+        activity.requests.fetch_add(1, atomic::Ordering::Relaxed);
+        let resp = self.handle_request(req, client, in_flight).await;
+        *activity.last_request.lock() = Instant::now();
+        activity.requests.fetch_sub(1, atomic::Ordering::Relaxed);
+        let resp = resp?;
+        let action = resp.extensions().get::<Action>().map_or("unknown", |action| action.0);
+        metrics.record(action, resp.status(), start.elapsed());
+        Ok(resp)
+    }
+
+    fn route(&self, path: &str) -> Route {
+        match path {
+            // The RPC path takes precedence, in case it was set to one of the others
+            path if path == &*self.rpc_path => Route::Rpc,
+            VERSION_PATH => Route::Version,
+            METRICS_PATH => Route::Metrics,
+            HEALTH_PATH => Route::Health,
+            READY_PATH => Route::Ready,
+            _ => Route::NotFound,
+        }
+    }
+
+    async fn handle_request(
+        self,
+        mut req: Request<Body>,
+        client: SocketAddr,
+        in_flight: usize,
+    ) -> hyper::Result<Response<Body>> {
+        let encoding = if self.compression {
+            req.headers()
+                .get(hyper::header::ACCEPT_ENCODING)
+                .and_then(|value| value.to_str().ok())
+                .and_then(compression::negotiate)
+        } else {
+            None
+        };
+        let route = self.route(req.uri().path());
+        let get = *req.method() == hyper::Method::GET;
+        let authorized = self.authorized(&req);
+        if route == Route::Metrics && get && authorized {
+            let gpu_hashrates: Vec<f64> = self.gpus.iter().map(|gpu| gpu.health().throughput().hashrate()).collect();
+            let body = hyper::body::Bytes::from(self.metrics.render(self.throughput.hashrate(), &gpu_hashrates));
+            let action = Action("metrics");
+            return Ok(self.respond(StatusCode::OK, "text/plain; version=0.0.4", body, encoding, action));
+        }
+        let mut action = Action("unknown");
+        let request_encoding = match req.headers().get(hyper::header::CONTENT_ENCODING) {
+            Some(value) => value.to_str().map_err(|_| ()).and_then(compression::Encoding::from_header),
+            None => Ok(None),
         };
-        let body_str = body.to_string();
-        let body_len = body_str.len();
-        let body = Body::from(body_str);
-        Ok(Response::builder()
-            .header(hyper::header::CONTENT_LENGTH, body_len)
-            .header(hyper::header::CONTENT_TYPE, "application/json")
+        let (status, body) = if route == Route::NotFound {
+            (
+                StatusCode::NOT_FOUND,
+                json!({
+                    "error": "Not found",
+                    "code": "NOT_FOUND",
+                    "hint": format!(
+                        "RPC requests are served at {}, and GET {}, {}, {} and {}",
+                        self.rpc_path, VERSION_PATH, METRICS_PATH, HEALTH_PATH, READY_PATH
+                    ),
+                }),
+            )
+        } else if route == Route::Health && get {
+            // Probes don't need the API key, these tell nothing about the server
+            action = Action("health");
+            (StatusCode::OK, json!({ "status": "ok" }))
+        } else if route == Route::Ready && get {
+            action = Action("ready");
+            if self.readiness.ready() {
+                (StatusCode::OK, json!({ "status": "ready" }))
+            } else {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    json!({
+                        "error": "Not ready",
+                        "code": "NOT_READY",
+                        "hint": "GPUs are still initializing, or one failed to (see logs)",
+                    }),
+                )
+            }
+        } else if !authorized
+            && (self.auth_actions.is_none()
+                || route == Route::Metrics
+                || (route == Route::Version && get && self.needs_key("version")))
+        {
+            Self::unauthorized(client)
+        } else if route == Route::Version && get {
+            action = Action("version");
+            (StatusCode::OK, version_info())
+        } else if route != Route::Rpc {
+            (
+                StatusCode::METHOD_NOT_ALLOWED,
+                json!({
+                    "error": format!("Can only GET {}", req.uri().path()),
+                    "code": "METHOD_NOT_ALLOWED",
+                }),
+            )
+        } else if *req.method() == hyper::Method::POST && !self.json_content_type(&req) {
+            (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                json!({
+                    "error": "Unsupported media type",
+                    "code": "UNSUPPORTED_MEDIA_TYPE",
+                    "hint": "Expecting a \"Content-Type: application/json\" header",
+                }),
+            )
+        } else if *req.method() == hyper::Method::POST && request_encoding.is_err() {
+            (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                json!({
+                    "error": "Unsupported content encoding",
+                    "code": "UNSUPPORTED_ENCODING",
+                    "hint": "Supported request encodings: gzip, deflate",
+                }),
+            )
+        } else if *req.method() == hyper::Method::POST && in_flight > self.max_in_flight {
+            info!("Rejected request from {}, {} already in flight on its connection", client, in_flight - 1);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({
+                    "error": "Too many requests in flight",
+                    "code": "TOO_MANY_IN_FLIGHT",
+                    "hint": format!(
+                        "At most {} requests can be in flight per connection (see --max-in-flight)",
+                        self.max_in_flight
+                    ),
+                }),
+            )
+        } else if *req.method() == hyper::Method::POST {
+            let self_copy = self.clone();
+            let body = match self.io_timeout {
+                Some(io_timeout) => tokio::time::timeout(io_timeout, hyper::body::to_bytes(req.body_mut())).await,
+                None => Ok(hyper::body::to_bytes(req.body_mut()).await),
+            };
+            let body = match body {
+                Ok(body) => body?,
+                Err(_) => {
+                    info!("Timed out reading the request from {}", client);
+                    let body = json!({
+                        "error": "Request timeout",
+                        "code": "REQUEST_TIMEOUT",
+                        "hint": "The request body took longer than --io-timeout to arrive",
+                    });
+                    let mut resp = self.respond(
+                        StatusCode::REQUEST_TIMEOUT,
+                        "application/json",
+                        self.buffers.serialize(&body),
+                        encoding,
+                        action,
+                    );
+                    resp.headers_mut()
+                        .insert(hyper::header::CONNECTION, hyper::header::HeaderValue::from_static("close"));
+                    return Ok(resp);
+                }
+            };
+            let body = match request_encoding {
+                Ok(Some(request_encoding)) => match request_encoding.decompress(&body) {
+                    Ok(body) => hyper::body::Bytes::from(body),
+                    Err(err) => {
+                        let (status, body) = match err {
+                            compression::DecompressError::TooLarge => (
+                                StatusCode::PAYLOAD_TOO_LARGE,
+                                json!({
+                                    "error": "Request body too large",
+                                    "code": "BODY_TOO_LARGE",
+                                    "hint": format!(
+                                        "The body can decompress to at most {} bytes",
+                                        compression::MAX_DECOMPRESSED_SIZE
+                                    ),
+                                }),
+                            ),
+                            compression::DecompressError::Invalid(err) => (
+                                StatusCode::BAD_REQUEST,
+                                json!({
+                                    "error": "Failed to decompress the request body",
+                                    "code": "BAD_ENCODING",
+                                    "hint": format!("{} ({})", err, request_encoding.name()),
+                                }),
+                            ),
+                        };
+                        let body = self.buffers.serialize(&body);
+                        return Ok(self.respond(status, "application/json", body, encoding, action));
+                    }
+                },
+                _ => body,
+            };
+            // Parsed once here, as the envelope and the metrics need the request too
+            let request = serde_json::from_slice::<Value>(&body).ok();
+            let (mut status, resp) = self_copy
+                .process_req(body.as_ref(), request.as_ref(), client, authorized)
+                .await?;
+            if self.strict_http_status && status == StatusCode::OK {
+                if let Some(strict) = resp.get("code").and_then(Value::as_str).and_then(strict_status) {
+                    status = strict;
+                }
+            }
+            action = Action::of(request.as_ref());
+            if self.jsonrpc {
+                (status, jsonrpc_envelope(request.as_ref(), resp))
+            } else {
+                (status, resp)
+            }
+        } else {
+            (
+                StatusCode::METHOD_NOT_ALLOWED,
+                json!({
+                    "error": "Can only POST requests",
+                    "code": "METHOD_NOT_ALLOWED",
+                }),
+            )
+        };
+        let serialize_start = Instant::now();
+        let body = self.buffers.serialize(&body);
+        if self.phase_metrics && action.0 == "work_generate" {
+            self.metrics.record_phase("serialize", serialize_start.elapsed());
+        }
+        let mut resp = self.respond(status, "application/json", body, encoding, action);
+        if status == StatusCode::METHOD_NOT_ALLOWED {
+            // A 405 response must list the methods the path does allow
+            let allow = if route == Route::Rpc { "POST" } else { "GET" };
+            resp.headers_mut()
+                .insert(hyper::header::ALLOW, hyper::header::HeaderValue::from_static(allow));
+        }
+        Ok(resp)
+    }
+
+    /// Builds a response, compressed if the client accepts it and it's worth it
+    fn respond(
+        &self,
+        status: StatusCode,
+        content_type: &str,
+        mut body: hyper::body::Bytes,
+        encoding: Option<compression::Encoding>,
+        action: Action,
+    ) -> Response<Body> {
+        let mut builder = Response::builder().extension(action);
+        if self.compression {
+            builder = builder.header(hyper::header::VARY, "Accept-Encoding");
+        }
+        if let Some(encoding) = encoding.filter(|_| body.len() >= compression::MIN_SIZE) {
+            body = hyper::body::Bytes::from(encoding.compress(&body));
+            builder = builder.header(hyper::header::CONTENT_ENCODING, encoding.name());
+        }
+        builder
+            .header(hyper::header::CONTENT_LENGTH, body.len())
+            .header(hyper::header::CONTENT_TYPE, content_type)
             .status(status)
-            .body(body)
-            .expect("Failed to build response"))
+            .body(Body::from(body))
+            .expect("Failed to build response")
+    }
+}
+
+fn bind_listener(addr: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // Allows rebinding while the previous process' socket is in TIME_WAIT.
+    // On Windows this would allow stealing a port in use, so it's left off there.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    // The OS caps the backlog, e.g. at net.core.somaxconn on Linux
+    socket.listen(backlog)
+}
+
+/// More CPU threads than this per core would only slow the machine down
+const MAX_THREADS_PER_CORE: usize = 4;
+
+/// Parses the value of a CPU thread count option, capping it so that a typo can't spawn
+/// enough threads to swamp the machine
+fn parse_cpu_threads(option: &str, value: &str) -> usize {
+    let threads: usize = value.parse().unwrap_or_else(|_| {
+        eprintln!("--{} must be a number of threads (got {:?})", option, value);
+        process::exit(1);
+    });
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let max_threads = cores * MAX_THREADS_PER_CORE;
+    if threads > max_threads {
+        warn!(
+            "--{} {} is more than {} threads for each of the {} cores, using {} threads",
+            option, threads, MAX_THREADS_PER_CORE, cores, max_threads
+        );
+        return max_threads;
+    }
+    threads
+}
+
+/// Parses and checks a `--gpu PLATFORM:DEVICE[:THREADS[:LOCALSIZE]]` string, returning the
+/// platform, device, threads and local work size to create the GPU with. `seen` holds the
+/// strings of the devices given so far, to catch one given twice.
+fn parse_gpu(
+    s: &str,
+    local_work_size: Option<usize>,
+    seen: &mut HashMap<(usize, usize), String>,
+    allow_duplicate: bool,
+    devices: &[(String, Vec<String>)],
+) -> Result<(usize, usize, usize, Option<usize>), String> {
+    let mut parts = s.split(':');
+    let platform = parts.next().unwrap_or_default();
+    if platform.is_empty() {
+        return Err(format!("GPU string {:?} is missing the platform", s));
+    }
+    let platform: usize = platform
+        .parse()
+        .map_err(|_| format!("Failed to parse GPU platform {:?} in string {:?}", platform, s))?;
+    let device = parts
+        .next()
+        .ok_or_else(|| {
+            format!(
+                "GPU string {:?} must be PLATFORM:DEVICE, PLATFORM:DEVICE:THREADS or PLATFORM:DEVICE:THREADS:LOCALSIZE",
+                s
+            )
+        })?;
+    let device: usize = device
+        .parse()
+        .map_err(|_| format!("Failed to parse GPU device {:?} in string {:?}", device, s))?;
+    let threads = parts.next().unwrap_or("1048576");
+    let threads: usize = threads
+        .parse()
+        .map_err(|_| format!("Failed to parse GPU threads {:?} in string {:?}", threads, s))?;
+    if threads == 0 || threads as u64 > 1 << 32 {
+        return Err(format!(
+            "GPU threads in string {:?} must be between 1 and 4294967296",
+            s
+        ));
+    }
+    // Falls back to --gpu-local-work-size when the device doesn't set its own
+    let local_work_size = match parts.next() {
+        Some(size) => Some(size.parse().map_err(|_| {
+            format!("Failed to parse GPU local work size {:?} in string {:?}", size, s)
+        })?),
+        None => local_work_size,
+    };
+    if local_work_size == Some(0) {
+        return Err(format!("GPU local work size in string {:?} must be at least 1", s));
+    }
+    if parts.next().is_some() {
+        return Err(format!("Too many colons in GPU string {:?}", s));
+    }
+    // Caught here, as OpenCL's own errors for these don't say what is wrong
+    if devices.is_empty() {
+        return Err("No OpenCL platforms exist (check your drivers and OpenCL setup)".to_string());
+    }
+    let (platform_name, platform_devices) = devices.get(platform).ok_or_else(|| {
+        format!(
+            "GPU platform {} in string {:?} doesn't exist, valid platforms are 0 to {} (see --list-gpus)",
+            platform,
+            s,
+            devices.len() - 1
+        )
+    })?;
+    if platform_devices.is_empty() {
+        return Err(format!(
+            "GPU platform {} ({}) in string {:?} has no devices (see --list-gpus)",
+            platform, platform_name, s
+        ));
+    }
+    if device >= platform_devices.len() {
+        return Err(format!(
+            "GPU device {} in string {:?} doesn't exist, valid devices of platform {} ({}) are 0 to {} (see --list-gpus)",
+            device,
+            s,
+            platform,
+            platform_name,
+            platform_devices.len() - 1
+        ));
+    }
+    if let Some(other) = seen.insert((platform, device), s.to_string()) {
+        let msg = format!(
+            "GPU platform {} device {} is given twice ({:?} and {:?}), so two workers would share it",
+            platform, device, other, s
+        );
+        if !allow_duplicate {
+            return Err(format!("{}. Pass --allow-duplicate-gpu if this is intended", msg));
+        }
+        warn!("{}", msg);
+    }
+    Ok((platform, device, threads, local_work_size))
+}
+
+/// The actions given to a repeatable option, exiting on unknown ones
+fn parse_actions(values: Option<clap::Values>, option: &str) -> Arc<[&'static str]> {
+    values
+        .into_iter()
+        .flatten()
+        .map(|action| {
+            // /health and /ready are paths rather than actions
+            match ACTIONS.iter().find(|&&name| name == action && !matches!(name, "health" | "ready")) {
+                Some(&name) => name,
+                None => {
+                    eprintln!("Unknown action {:?} given to {}", action, option);
+                    process::exit(1);
+                }
+            }
+        })
+        .collect()
+}
+
+/// Writes our PID to `path`, overwriting a stale pidfile left by an unclean exit
+fn write_pidfile(path: &Path) {
+    if let Ok(old) = fs::read_to_string(path) {
+        warn!(
+            "Overwriting existing pidfile {} (PID {})",
+            path.display(),
+            old.trim()
+        );
+    }
+    if let Err(err) = fs::write(path, format!("{}\n", process::id())) {
+        error!("Failed to write pidfile {}: {}", path.display(), err);
+        process::exit(1);
+    }
+}
+
+/// Writes the listening port to `path`, through a rename so it's never read half written
+fn write_port_file(path: &Path, port: u16) {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    if let Err(err) = fs::write(&tmp, format!("{}\n", port)).and_then(|_| fs::rename(&tmp, path)) {
+        error!("Failed to write port file {}: {}", path.display(), err);
+        process::exit(1);
+    }
+}
+
+/// Resolves once the server should shut down, on Ctrl-C, SIGTERM or a `shutdown` request
+async fn shutdown_signal(remote: Arc<Notify>) {
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<Option<()>>();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received Ctrl-C, shutting down"),
+        _ = terminate => info!("Received SIGTERM, shutting down"),
+        _ = remote.notified() => info!("Shutdown requested, shutting down"),
     }
 }
 
+/// Binds the listener, retrying with exponential backoff for up to `retry_for`
+async fn bind_listener_retrying(
+    addr: SocketAddr,
+    backlog: u32,
+    retry_for: Duration,
+) -> io::Result<TcpListener> {
+    let start = Instant::now();
+    let mut delay = Duration::from_millis(250);
+    loop {
+        match bind_listener(addr, backlog) {
+            Ok(listener) => return Ok(listener),
+            Err(err) if start.elapsed() + delay <= retry_for => {
+                warn!(
+                    "Failed to bind to {}: {}, retrying in {}ms",
+                    addr,
+                    err,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(10));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The connection settings of the HTTP server
+struct ServerConfig {
+    connection_limit: Option<Arc<Semaphore>>,
+    keep_alive_timeout: Option<Duration>,
+    io_timeout: Option<Duration>,
+    /// Also accept cleartext HTTP/2 with prior knowledge (`--http2`)
+    http2: bool,
+}
+
+/// Serves `service` on `listener` until a shutdown is requested, by a signal or over RPC
+async fn run_server(service: RpcService, listener: TcpListener, config: ServerConfig) -> hyper::Result<()> {
+    let work_state = service.work_state.clone();
+    let incoming = connection::incoming(
+        listener,
+        config.connection_limit,
+        service.connections.clone(),
+        config.keep_alive_timeout,
+        config.io_timeout,
+    );
+    let make_service = hyper::service::make_service_fn(|conn: &Connection| {
+        let service = service.clone();
+        let conn_state = conn.state();
+        let client = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(move |req| {
+                let guard = RequestGuard::new(conn_state.clone());
+                let in_flight = guard.in_flight();
+                let service = service.clone();
+                async move {
+                    let resp = service.serve(req, client, in_flight).await;
+                    drop(guard);
+                    resp
+                }
+            }))
+        }
+    });
+    let shutdown = shutdown_signal(service.shutdown.clone());
+    let mut builder = Server::builder(hyper::server::accept::from_stream(incoming))
+        .http1_keepalive(config.keep_alive_timeout.is_some())
+        .http1_only(!config.http2);
+    if let Some(io_timeout) = config.io_timeout {
+        builder = builder.http1_header_read_timeout(io_timeout);
+    }
+    builder
+        .serve(make_service)
+        .with_graceful_shutdown(async move {
+            shutdown.await;
+            // Lets the connections waiting on work finish, so the server can stop
+            work_state.0.lock().cancel_all();
+        })
+        .await
+}
+
 #[tokio::main]
 async fn main() {
     let args = clap::App::new("Nano work server")
@@ -472,129 +3120,824 @@ async fn main() {
                 .default_value("[::1]:7076")
                 .help("Specifies the address to listen on."),
         )
+        .arg(
+            clap::Arg::with_name("bind_retry")
+                .long("bind-retry")
+                .value_name("SECONDS")
+                .default_value("0")
+                .help("Keep retrying to bind the listen address for this long, with exponential backoff."),
+        )
+        .arg(
+            clap::Arg::with_name("listen_backlog")
+                .long("listen-backlog")
+                .value_name("N")
+                .default_value("1024")
+                .help("How many connections may wait to be accepted. The OS caps this, e.g. at net.core.somaxconn on Linux."),
+        )
+        .arg(
+            clap::Arg::with_name("rpc_path")
+                .long("rpc-path")
+                .value_name("PATH")
+                .default_value("/")
+                .help("The HTTP path RPC requests are served at. Other paths return 404."),
+        )
+        .arg(
+            clap::Arg::with_name("api_key")
+                .long("api-key")
+                .value_name("KEY")
+                .help("Require requests to carry an \"Authorization: Bearer KEY\" header."),
+        )
+        .arg(
+            clap::Arg::with_name("auth_actions")
+                .long("auth-actions")
+                .value_name("ACTIONS")
+                .use_delimiter(true)
+                .multiple(true)
+                .requires("api_key")
+                .help("Only require the --api-key for these comma separated actions, e.g. benchmark,status, leaving the others open to everyone. queue_dump, config_dump, shutdown and /metrics always require it."),
+        )
+        .arg(
+            clap::Arg::with_name("allow_remote_shutdown")
+                .long("allow-remote-shutdown")
+                .requires("api_key")
+                .help("Enable the shutdown action, which shuts the server down like SIGTERM. Requires --api-key."),
+        )
+        .arg(
+            clap::Arg::with_name("max_connections")
+                .long("max-connections")
+                .value_name("N")
+                .help("The maximum number of open HTTP connections. Further connections wait until one closes."),
+        )
+        .arg(
+            clap::Arg::with_name("max_in_flight")
+                .long("max-in-flight")
+                .value_name("N")
+                .default_value("64")
+                .help("The maximum number of requests in flight on one HTTP/2 connection. Further requests are rejected until some complete. Only matters with --http2."),
+        )
+        .arg(
+            clap::Arg::with_name("response_buffers")
+                .long("response-buffers")
+                .value_name("N")
+                .default_value("64")
+                .help("How many buffers to keep for serializing responses, saving allocations at high request rates. 0 keeps none."),
+        )
+        .arg(
+            clap::Arg::with_name("disable_action")
+                .long("disable-action")
+                .value_name("ACTION")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Reject this RPC action, e.g. benchmark or status on a server facing untrusted clients. Can be given several times."),
+        )
+        .arg(
+            clap::Arg::with_name("max_request_rate_global")
+                .long("max-request-rate-global")
+                .value_name("N")
+                .help("Reject work_generate requests with 503 while more than N requests per second come in from all clients together. Other actions are still served."),
+        )
+        .arg(
+            clap::Arg::with_name("keep_alive_timeout")
+                .long("keep-alive-timeout")
+                .value_name("SECONDS")
+                .default_value("60")
+                .help("Close connections without a request in flight after this long without traffic. 0 disables keep-alive. Idle connections count towards --max-connections until closed."),
+        )
+        .arg(
+            clap::Arg::with_name("http2")
+                .long("http2")
+                .help("Also accept cleartext HTTP/2 (h2c) from clients that start with it, to multiplex requests over one connection."),
+        )
+        .arg(
+            clap::Arg::with_name("io_timeout")
+                .long("io-timeout")
+                .value_name("SECONDS")
+                .default_value("120")
+                .help("Close connections whose request headers or body take longer than this to arrive, or whose client stops reading the response this long. 0 disables it."),
+        )
+        .arg(
+            clap::Arg::with_name("result_cache_size")
+                .long("result-cache-size")
+                .value_name("ROOTS")
+                .default_value("100000")
+                .help("Keep the work of up to this many roots, generated ahead of time or for requests, for work_peek and repeated requests. The least recently used is dropped first."),
+        )
+        .arg(
+            clap::Arg::with_name("result_ttl")
+                .long("result-ttl")
+                .value_name("SECONDS")
+                .help("Forget stored work after this long. Kept until evicted by default."),
+        )
+        .arg(
+            clap::Arg::with_name("phase_metrics")
+                .long("phase-metrics")
+                .help("Also time how long work waits in the queue, for a worker, and to be generated, and how long responses take to serialize, in /metrics."),
+        )
+        .arg(
+            clap::Arg::with_name("idle_shutdown_secs")
+                .long("idle-shutdown-secs")
+                .value_name("SECONDS")
+                .help("Shut down gracefully once no request came in for this long, e.g. for instances started on demand."),
+        )
+        .arg(
+            clap::Arg::with_name("heartbeat_secs")
+                .long("heartbeat-secs")
+                .value_name("SECONDS")
+                .help("Log a summary of the queue, the current work, the hashrate and the GPUs' invalid work this often."),
+        )
+        .arg(
+            clap::Arg::with_name("slow_request_ms")
+                .long("slow-request-ms")
+                .value_name("MILLISECONDS")
+                .default_value("30000")
+                .help("Log a warning for each work_generate taking longer than this, with its root and threshold. 0 disables it."),
+        )
+        .arg(
+            clap::Arg::with_name("work_timeout")
+                .long("work-timeout")
+                .value_name("SECONDS")
+                .help("Give up on a work_generate that wasn't answered after this long, queued or generating."),
+        )
+        .arg(
+            clap::Arg::with_name("upstream")
+                .long("upstream")
+                .value_name("URL")
+                .help("Forward work_generate requests that fail locally, e.g. because every GPU errored, to the work server at this http:// URL."),
+        )
+        .arg(
+            clap::Arg::with_name("peer")
+                .long("peer")
+                .value_name("URL")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("fanout")
+                .help("A work server at this http:// URL to race work_generate against, with --fanout. Can be given several times."),
+        )
+        .arg(
+            clap::Arg::with_name("fanout")
+                .long("fanout")
+                .requires("peer")
+                .help("Send each work_generate to the --peer servers too, while the workers generate it, and answer with whichever work comes first. The rest is cancelled."),
+        )
+        .arg(
+            clap::Arg::with_name("webhook")
+                .long("webhook")
+                .value_name("URL")
+                .help("POST {\"hash\", \"work\", \"threshold\"} to this http:// URL for each work_generate answered with work, in the background. Failed deliveries are retried twice, then logged."),
+        )
+        .arg(
+            clap::Arg::with_name("upstream_timeout")
+                .long("upstream-timeout")
+                .value_name("SECONDS")
+                .default_value("30")
+                .help("How long to wait for the --upstream and --peer work servers."),
+        )
+        .arg(
+            clap::Arg::with_name("strict_content_type")
+                .long("strict-content-type")
+                .help("Reject requests without a \"Content-Type: application/json\" header with 415 Unsupported Media Type."),
+        )
+        .arg(
+            clap::Arg::with_name("strict_http_status")
+                .long("strict-http-status")
+                .help("Answer errors such as CANCELLED or GENERATION_FAILED with a matching HTTP status instead of 200 OK. The JSON body stays the same."),
+        )
+        .arg(
+            clap::Arg::with_name("jsonrpc")
+                .long("jsonrpc")
+                .help("Wrap responses in JSON-RPC 2.0 envelopes, echoing the request's \"id\"."),
+        )
+        .arg(
+            clap::Arg::with_name("numeric_json")
+                .long("numeric-json")
+                .help("Give counts, durations and multipliers in responses as JSON numbers instead of strings."),
+        )
+        .arg(
+            clap::Arg::with_name("enable_compression")
+                .long("enable-compression")
+                .help("Compress large responses with gzip or deflate when the client's Accept-Encoding allows it."),
+        )
         .arg(
             clap::Arg::with_name("cpu_threads")
                 .short("c")
                 .long("cpu-threads")
                 .value_name("THREADS")
                 .default_value("0")
-                .help("Specifies how many CPU threads to use."),
+                .help("Specifies how many CPU threads to use. \"auto\" uses one per core, minus one per --gpu, and at least one."),
+        )
+        .arg(
+            clap::Arg::with_name("numa_node")
+                .long("numa-node")
+                .value_name("N")
+                .help("Run CPU threads on the CPUs of this NUMA node only, with their memory allocated from it. Linux only."),
+        )
+        .arg(
+            clap::Arg::with_name("cpu_batch_size")
+                .long("cpu-batch-size")
+                .value_name("N")
+                .default_value("262144")
+                .help("How many nonces a CPU thread tries between checks for a new task. Larger batches lock less often on many-core machines, smaller ones pick up cancellations sooner."),
+        )
+        .arg(
+            clap::Arg::with_name("fallback_cpu_threads")
+                .long("fallback-cpu-threads")
+                .value_name("THREADS")
+                .default_value("0")
+                .help("CPU threads that only generate work when a GPU fails to set up a request, so it completes slowly instead of failing."),
         )
         .arg(
             clap::Arg::with_name("gpu")
                 .short("g")
                 .long("gpu")
-                .value_name("PLATFORM:DEVICE:THREADS")
+                .value_name("PLATFORM:DEVICE:THREADS:LOCALSIZE")
                 .multiple(true)
-                .help("Specifies which GPU(s) to use. THREADS is optional and defaults to 1048576."),
+                .help("Specifies which GPU(s) to use. THREADS is optional and defaults to 1048576. LOCALSIZE is optional and defaults to --gpu-local-work-size."),
+        )
+        .arg(
+            clap::Arg::with_name("list_gpus")
+                .long("list-gpus")
+                .help("Print the OpenCL platforms and devices, with the indexes to pass to --gpu, and exit."),
+        )
+        .arg(
+            clap::Arg::with_name("require_all_gpus")
+                .long("require-all-gpus")
+                .help("Exit if any --gpu fails to initialize, e.g. for lack of memory, instead of skipping it and starting with the others."),
+        )
+        .arg(
+            clap::Arg::with_name("allow_duplicate_gpu")
+                .long("allow-duplicate-gpu")
+                .help("Only warn, instead of exiting, when the same GPU platform and device is given to --gpu more than once"),
+        )
+        .arg(
+            clap::Arg::with_name("reject_flaky_gpu")
+                .long("reject-flaky-gpu")
+                .value_name("RATE")
+                .help("Permanently disable a GPU once more than this share (0 to 1) of its results are invalid work, judged after at least 10 results."),
+        )
+        .arg(
+            clap::Arg::with_name("gpu_error_tolerance")
+                .long("gpu-error-tolerance")
+                .value_name("N")
+                .default_value("3")
+                .help("Abandon a request on a GPU after this many consecutive errors. It takes part again in the next request."),
+        )
+        .arg(
+            clap::Arg::with_name("gpu_invalid_tolerance")
+                .long("gpu-invalid-tolerance")
+                .value_name("N")
+                .default_value("3")
+                .help("Abandon a request on a GPU after it returned invalid work this many consecutive times. It takes part again in the next request."),
+        )
+        .arg(
+            clap::Arg::with_name("gpu_max_errors")
+                .long("gpu-max-errors")
+                .value_name("N")
+                .help("Permanently disable a GPU after this many errors and invalid results in total. Unlimited by default."),
         )
         .arg(
             clap::Arg::with_name("gpu_local_work_size")
                 .long("gpu-local-work-size")
                 .value_name("N")
-                .help("The GPU local work size. Increasing it may increase performance. For advanced users only."),
+                .help("The GPU local work size, for GPUs that don't set their own in --gpu. Increasing it may increase performance. For advanced users only."),
+        )
+        .arg(
+            clap::Arg::with_name("gpu_dispatch_multiplier")
+                .long("gpu-dispatch-multiplier")
+                .value_name("N")
+                .default_value("1")
+                .help("How many dispatches a GPU runs back to back between checks for a new task. Larger values may keep a GPU with few threads busier, but a cancelled or completed request holds it for up to N dispatches."),
         )
         .arg(
             clap::Arg::with_name("shuffle")
                 .long("shuffle")
                 .help("Pick a random request from the queue instead of the oldest. Increases efficiency when using multiple work servers")
         )
+        .arg(
+            clap::Arg::with_name("double_check")
+                .long("double-check")
+                .help("Check all work found again with a second, independent Blake2b implementation before sending it, discarding work that fails.")
+        )
+        .arg(
+            clap::Arg::with_name("track_progress")
+                .long("track-progress")
+                .help("Have CPU threads keep track of the best work found for every request, reported as current_best_multiplier in status. Costs a comparison per nonce.")
+        )
+        .arg(
+            clap::Arg::with_name("all_workers")
+                .long("all-workers")
+                .help("Put every worker on each request, for the lowest latency on very hard thresholds, instead of giving it to the fastest idle GPU alone."),
+        )
+        .arg(
+            clap::Arg::with_name("fair_queue")
+                .long("fair-queue")
+                .conflicts_with("shuffle")
+                .help("Take turns between client IPs when picking the next request from the queue, so one client's batch can't hold up the others")
+        )
+        .arg(
+            clap::Arg::with_name("warmup")
+                .long("warmup")
+                .help("Keep GPUs warm while idle by periodically running a dispatch that cannot find work."),
+        )
+        .arg(
+            clap::Arg::with_name("warmup_interval")
+                .long("warmup-interval")
+                .value_name("SECONDS")
+                .default_value("30")
+                .help("How long a GPU may be idle before a warmup dispatch is run (with --warmup)."),
+        )
+        .arg(
+            clap::Arg::with_name("max_attempts")
+                .long("max-attempts")
+                .value_name("N")
+                .help("Abandon a request once the workers have tried this many nonces without finding work. Unlimited by default."),
+        )
+        .arg(
+            clap::Arg::with_name("max_benchmark_count")
+                .long("max-benchmark-count")
+                .value_name("N")
+                .default_value("10000")
+                .help("Reject benchmarks with a higher count."),
+        )
+        .arg(
+            clap::Arg::with_name("base_difficulty")
+                .long("base-difficulty")
+                .value_name("HEX")
+                .help("The base threshold that multipliers are relative to. Defaults to the Vite base threshold ffffffc0000000..."),
+        )
+        .arg(
+            clap::Arg::with_name("startup_bench")
+                .long("startup-bench")
+                .conflicts_with("self_test")
+                .help("Generate work for a few seconds before listening, and log the hashrate of each worker."),
+        )
+        .arg(
+            clap::Arg::with_name("self_test")
+                .long("self-test")
+                .help("Generate and verify work for a few random roots with every worker, print the results and exit. Exits with 1 if anything failed."),
+        )
+        .arg(
+            clap::Arg::with_name("raw_work")
+                .long("raw-work")
+                .help("Debugging aid: send and expect work in the byte order it is hashed in, instead of reversed. Requests can still pick an order with \"endian\"."),
+        )
+        .arg(
+            clap::Arg::with_name("difficulty_name")
+                .long("difficulty-name")
+                .value_name("NAME=THRESHOLD")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Let requests give this threshold as \"difficulty_name\": \"NAME\" instead of a threshold or multiplier, e.g. send=fffffff800000000000000000000000000000000000000000000000000000000. Can be given several times."),
+        )
+        .arg(
+            clap::Arg::with_name("min_multiplier")
+                .long("min-multiplier")
+                .value_name("MULTIPLIER")
+                .help("Generate work for at least this multiplier of the base threshold, raising weaker requested thresholds. Guards against client bugs requesting unusable work."),
+        )
+        .arg(
+            clap::Arg::with_name("max_generation_multiplier")
+                .long("max-generation-multiplier")
+                .value_name("MULTIPLIER")
+                .default_value("1024")
+                .help("Reject work_generate requests for more than this multiplier of the base threshold, so a client can't tie up the workers for minutes. 0 disables the cap."),
+        )
+        .arg(
+            clap::Arg::with_name("precompute_file")
+                .long("precompute-file")
+                .value_name("PATH")
+                .help("Generate work ahead of time for the roots in this file (\"-\" for stdin), one per line with an optional threshold, while no request is waiting. The file is read again when it changes. Fetch the work with work_peek, or just work_generate."),
+        )
+        .arg(
+            clap::Arg::with_name("hash_length")
+                .long("hash-length")
+                .value_name("BYTES")
+                .default_value("32")
+                .help("The Blake2b digest length in bytes (1 to 32). Shorter digests are compared against the start of the threshold."),
+        )
+        .arg(
+            clap::Arg::with_name("hash_key")
+                .long("hash-key")
+                .value_name("HEX")
+                .help("A key for a keyed Blake2b work hash (up to 64 bytes). Not used by Nano or Vite."),
+        )
+        .arg(
+            clap::Arg::with_name("hash_personal")
+                .long("hash-personal")
+                .value_name("HEX")
+                .help("A Blake2b personalization string for the work hash (up to 16 bytes). Not used by Nano or Vite."),
+        )
+        .arg(
+            clap::Arg::with_name("pidfile")
+                .long("pidfile")
+                .value_name("PATH")
+                .help("Write the process ID to this file once ready, and remove it on shutdown."),
+        )
+        .arg(
+            clap::Arg::with_name("port_file")
+                .long("port-file")
+                .value_name("PATH")
+                .help("Write the port listened on to this file once ready. Useful with port 0, which picks a free port."),
+        )
+        .arg(
+            clap::Arg::with_name("log_file")
+                .long("log-file")
+                .value_name("PATH")
+                .help("Write logs to this file instead of stdout. Warnings and errors are still written to stderr too."),
+        )
+        .arg(
+            clap::Arg::with_name("log_max_size")
+                .long("log-max-size")
+                .value_name("BYTES")
+                .help("Rotate the log file to PATH.1 once it would grow past this size (with --log-file)."),
+        )
+        .arg(
+            clap::Arg::with_name("no_stderr")
+                .long("no-stderr")
+                .help("Don't also write warnings and errors to stderr (with --log-file)."),
+        )
         .get_matches();
+    let positive_arg = |name: &str, flag: &str| -> u64 {
+        match args.value_of(name).unwrap().parse() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("--{} must be a positive integer", flag);
+                process::exit(1);
+            }
+        }
+    };
+    let non_negative_arg = |name: &str, flag: &str| -> u64 {
+        args.value_of(name).unwrap().parse().unwrap_or_else(|_| {
+            eprintln!("--{} must be a non-negative integer", flag);
+            process::exit(1);
+        })
+    };
+    let hex_arg = |name: &str, flag: &str| -> Vec<u8> {
+        args.value_of(name).map_or_else(Vec::new, |s| {
+            hex::decode(s).unwrap_or_else(|_| {
+                eprintln!("--{} must be hex", flag);
+                process::exit(1);
+            })
+        })
+    };
+    let log_max_size = args
+        .value_of("log_max_size")
+        .map(|_| non_negative_arg("log_max_size", "log-max-size"));
+    let log_file = args.value_of("log_file").map(PathBuf::from);
+    if let Err(err) = logging::init(log_file, log_max_size, !args.is_present("no_stderr")) {
+        eprintln!("Failed to open log file: {}", err);
+        process::exit(1);
+    }
+    if args.is_present("list_gpus") {
+        let devices = gpu::list_devices();
+        if devices.is_empty() {
+            println!("No OpenCL platforms exist (check your drivers and OpenCL setup)");
+        }
+        for (platform, (platform_name, devices)) in devices.iter().enumerate() {
+            println!("Platform {}: {}", platform, platform_name);
+            for (device, device_name) in devices.iter().enumerate() {
+                println!("    --gpu {}:{}  {}", platform, device, device_name);
+            }
+        }
+        return;
+    }
     let random_mode = args.is_present("shuffle");
-    let listen_addr = args
-        .value_of("listen_address")
-        .unwrap()
-        .parse()
-        .expect("Failed to parse listen address");
-    let cpu_threads: usize = args
-        .value_of("cpu_threads")
+    let listen_addr: SocketAddr = args.value_of("listen_address").unwrap().parse().unwrap_or_else(|_| {
+        eprintln!("--listen-address must be an IP address and port, e.g. [::1]:7076");
+        process::exit(1);
+    });
+    let bind_retry = non_negative_arg("bind_retry", "bind-retry");
+    // The OS caps the backlog far below u32::MAX anyway
+    let listen_backlog = u32::try_from(non_negative_arg("listen_backlog", "listen-backlog")).unwrap_or(u32::MAX);
+    let rpc_path = args.value_of("rpc_path").unwrap();
+    let rpc_path: Arc<str> = if rpc_path.starts_with('/') {
+        rpc_path.into()
+    } else {
+        format!("/{}", rpc_path).into()
+    };
+    let keep_alive_timeout = non_negative_arg("keep_alive_timeout", "keep-alive-timeout");
+    let keep_alive_timeout = Some(Duration::from_secs(keep_alive_timeout)).filter(|t| !t.is_zero());
+    let io_timeout = non_negative_arg("io_timeout", "io-timeout");
+    let io_timeout = Some(Duration::from_secs(io_timeout)).filter(|t| !t.is_zero());
+    let slow_request = non_negative_arg("slow_request_ms", "slow-request-ms");
+    let slow_request = Some(Duration::from_millis(slow_request)).filter(|t| !t.is_zero());
+    let work_timeout = args
+        .value_of("work_timeout")
+        .map(|_| Duration::from_secs(positive_arg("work_timeout", "work-timeout")));
+    let upstream_timeout: u64 = match args.value_of("upstream_timeout").unwrap().parse() {
+        Ok(timeout) if timeout > 0 => timeout,
+        _ => {
+            eprintln!("--upstream-timeout must be a positive number of seconds");
+            process::exit(1);
+        }
+    };
+    let upstream = args.value_of("upstream").map(|url| {
+        match upstream::Upstream::new(url, Duration::from_secs(upstream_timeout)) {
+            Ok(upstream) => Arc::new(upstream),
+            Err(err) => {
+                eprintln!("Invalid --upstream URL {:?}: {}", url, err);
+                process::exit(1);
+            }
+        }
+    });
+    let webhook = args.value_of("webhook").map(|url| match webhook::Webhook::new(url) {
+        Ok(webhook) => Arc::new(webhook),
+        Err(err) => {
+            eprintln!("Invalid --webhook URL {:?}: {}", url, err);
+            process::exit(1);
+        }
+    });
+    let peers: Arc<[upstream::Upstream]> = args
+        .values_of("peer")
+        .into_iter()
+        .flatten()
+        .map(|url| match upstream::Upstream::new(url, Duration::from_secs(upstream_timeout)) {
+            Ok(peer) => peer,
+            Err(err) => {
+                eprintln!("Invalid --peer URL {:?}: {}", url, err);
+                process::exit(1);
+            }
+        })
+        .collect();
+    let max_in_flight: usize = match args.value_of("max_in_flight").unwrap().parse() {
+        Ok(max) if max > 0 => max,
+        _ => {
+            eprintln!("--max-in-flight must be a positive integer");
+            process::exit(1);
+        }
+    };
+    if args.occurrences_of("max_in_flight") > 0 && !args.is_present("http2") {
+        warn!("--max-in-flight has no effect without --http2, as an HTTP/1 connection carries one request at a time");
+    }
+    let response_buffers: usize = args
+        .value_of("response_buffers")
         .unwrap()
         .parse()
-        .expect("Failed to parse CPU threads");
-    let gpu_local_work_size = args.value_of("gpu_local_work_size").map(|s| {
-        s.parse()
-            .expect("Failed to parse GPU local work size option")
+        .unwrap_or_else(|_| {
+            eprintln!("--response-buffers must be a non-negative integer");
+            process::exit(1);
+        });
+    let disabled_actions = parse_actions(args.values_of("disable_action"), "--disable-action");
+    let auth_actions = args
+        .values_of("auth_actions")
+        .map(|actions| parse_actions(Some(actions), "--auth-actions"));
+    let request_rate = args.value_of("max_request_rate_global").map(|s| match s.parse() {
+        Ok(limit) if limit > 0 => Arc::new(ratelimit::RequestRate::new(limit)),
+        _ => {
+            eprintln!("--max-request-rate-global must be a positive integer");
+            process::exit(1);
+        }
+    });
+    let connection_limit = args
+        .value_of("max_connections")
+        .map(|_| Arc::new(Semaphore::new(positive_arg("max_connections", "max-connections") as usize)));
+    let cpu_threads = match args.value_of("cpu_threads").unwrap() {
+        "auto" => {
+            // Each GPU's thread keeps a core busy driving it
+            let cores = thread::available_parallelism().map_or(1, |n| n.get());
+            let gpus = args.values_of("gpu").map_or(0, |gpus| gpus.count());
+            let threads = cores.saturating_sub(gpus).max(1);
+            info!(
+                "Using {} CPU threads for --cpu-threads auto ({} cores, minus 1 for each of the {} GPUs)",
+                threads, cores, gpus
+            );
+            threads
+        }
+        value => parse_cpu_threads("cpu-threads", value),
+    };
+    let numa_node: Option<(usize, Arc<[usize]>)> = args.value_of("numa_node").and_then(|s| {
+        let node: usize = match s.parse() {
+            Ok(node) => node,
+            Err(_) => {
+                eprintln!("--numa-node must be a NUMA node index");
+                process::exit(1);
+            }
+        };
+        if !numa::SUPPORTED {
+            warn!("--numa-node is only supported on Linux, ignoring it");
+            return None;
+        }
+        match numa::node_cpus(node) {
+            Ok(cpus) => {
+                info!(
+                    "Binding CPU threads and their memory to NUMA node {} (CPUs {})",
+                    node,
+                    cpus.iter().map(|cpu| cpu.to_string()).collect::<Vec<_>>().join(","),
+                );
+                Some((node, cpus.into()))
+            }
+            Err(err) => {
+                eprintln!("Failed to read the CPUs of NUMA node {}: {}", node, err);
+                process::exit(1);
+            }
+        }
+    });
+    let cpu_batch_size = positive_arg("cpu_batch_size", "cpu-batch-size");
+    let gpu_dispatch_multiplier = positive_arg("gpu_dispatch_multiplier", "gpu-dispatch-multiplier");
+    let fallback_cpu_threads = parse_cpu_threads(
+        "fallback-cpu-threads",
+        args.value_of("fallback_cpu_threads").unwrap(),
+    );
+    // WorkHasher checks the length is one BLAKE2b supports
+    let hash_length = non_negative_arg("hash_length", "hash-length") as usize;
+    let hash_key = hex_arg("hash_key", "hash-key");
+    let hash_personal = hex_arg("hash_personal", "hash-personal");
+    let hasher = Arc::new(
+        WorkHasher::new(hash_length, &hash_key, &hash_personal).unwrap_or_else(|err| {
+            eprintln!("Invalid hash parameters: {}", err);
+            process::exit(1);
+        }),
+    );
+    let base_threshold = match args.value_of("base_difficulty") {
+        Some(s) => {
+            let mut out = [0u8; 32];
+            if RpcService::parse_hex_json(&Value::from(s), &mut out, false).is_err() {
+                eprintln!("--base-difficulty must be a 32 byte hex threshold");
+                process::exit(1);
+            }
+            out
+        }
+        None => difficulty::BASE_THRESHOLD,
+    };
+    let mut difficulty_names = HashMap::new();
+    for s in args.values_of("difficulty_name").into_iter().flatten() {
+        let mut threshold = [0u8; 32];
+        let name = match s.split_once('=') {
+            Some((name, hex))
+                if !name.is_empty()
+                    && RpcService::parse_hex_json(&Value::from(hex), &mut threshold, false).is_ok() =>
+            {
+                name
+            }
+            _ => {
+                eprintln!("--difficulty-name must be NAME=THRESHOLD with a 32 byte hex threshold (got {:?})", s);
+                process::exit(1);
+            }
+        };
+        if difficulty_names.insert(name.to_string(), threshold).is_some() {
+            eprintln!("--difficulty-name {} is given twice", name);
+            process::exit(1);
+        }
+    }
+    let min_threshold = args.value_of("min_multiplier").map(|s| {
+        match s.parse::<f64>() {
+            Ok(multiplier) if multiplier.is_finite() && multiplier > 0.0 => {
+                difficulty::from_multiplier(multiplier, base_threshold)
+            }
+            _ => {
+                eprintln!("--min-multiplier must be a positive number");
+                process::exit(1);
+            }
+        }
+    });
+    let max_threshold = match args.value_of("max_generation_multiplier").unwrap().parse::<f64>() {
+        Ok(0.0) => None,
+        Ok(multiplier) if multiplier.is_finite() && multiplier > 0.0 => {
+            Some(difficulty::from_multiplier(multiplier, base_threshold))
+        }
+        _ => {
+            eprintln!("--max-generation-multiplier must be a positive number, or 0");
+            process::exit(1);
+        }
+    };
+    if let (Some(min_threshold), Some(max_threshold)) = (min_threshold, max_threshold) {
+        if min_threshold > max_threshold {
+            eprintln!("--min-multiplier can't be above --max-generation-multiplier");
+            process::exit(1);
+        }
+    }
+    let precompute_file = args.value_of("precompute_file").map(PathBuf::from);
+    if let Some(path) = &precompute_file {
+        if path != Path::new("-") && !path.is_file() {
+            eprintln!("--precompute-file {} doesn't exist", path.display());
+            process::exit(1);
+        }
+    }
+    let warmup_interval = if args.is_present("warmup") {
+        let secs = non_negative_arg("warmup_interval", "warmup-interval");
+        Some(Duration::from_secs(secs.max(1)))
+    } else {
+        None
+    };
+    let max_benchmark_count: u64 = match args.value_of("max_benchmark_count").unwrap().parse() {
+        Ok(count) if count > 0 => count,
+        _ => {
+            eprintln!("--max-benchmark-count must be a positive integer");
+            process::exit(1);
+        }
+    };
+    let double_check = args.is_present("double_check");
+    let max_attempts = args
+        .value_of("max_attempts")
+        .map(|_| positive_arg("max_attempts", "max-attempts"));
+    let reject_flaky_gpu: Option<f64> = args.value_of("reject_flaky_gpu").map(|s| {
+        match s.parse() {
+            Ok(rate) if (0.0..=1.0).contains(&rate) => rate,
+            _ => {
+                eprintln!("--reject-flaky-gpu must be a rate between 0 and 1");
+                process::exit(1);
+            }
+        }
     });
-    let gpus: Vec<Gpu> = args
+    let gpu_error_tolerance = positive_arg("gpu_error_tolerance", "gpu-error-tolerance");
+    let gpu_invalid_tolerance = positive_arg("gpu_invalid_tolerance", "gpu-invalid-tolerance");
+    let gpu_max_errors: Option<u64> = args
+        .value_of("gpu_max_errors")
+        .map(|_| positive_arg("gpu_max_errors", "gpu-max-errors"));
+    let result_cache_size = positive_arg("result_cache_size", "result-cache-size") as usize;
+    let result_ttl = args
+        .value_of("result_ttl")
+        .map(|_| Duration::from_secs(positive_arg("result_ttl", "result-ttl")));
+    let idle_shutdown = args
+        .value_of("idle_shutdown_secs")
+        .map(|_| Duration::from_secs(positive_arg("idle_shutdown_secs", "idle-shutdown-secs")));
+    let heartbeat = args
+        .value_of("heartbeat_secs")
+        .map(|_| Duration::from_secs(positive_arg("heartbeat_secs", "heartbeat-secs")));
+    let gpu_local_work_size = args
+        .value_of("gpu_local_work_size")
+        .map(|_| positive_arg("gpu_local_work_size", "gpu-local-work-size") as usize);
+    let allow_duplicate_gpu = args.is_present("allow_duplicate_gpu");
+    let devices = if args.is_present("gpu") {
+        gpu::list_devices()
+    } else {
+        Vec::new()
+    };
+    let mut gpu_errors = Vec::new();
+    let mut seen_gpus = HashMap::new();
+    let gpu_specs: Vec<_> = args
         .values_of("gpu")
-        .map(|x| x.collect())
-        .unwrap_or_else(Vec::new)
+        .map(|x| x.collect::<Vec<_>>())
+        .unwrap_or_default()
         .into_iter()
-        .map(|s| {
-            let mut parts = s.split(':');
-            let platform = parts
-                .next()
-                .expect("GPU string cannot be blank")
-                .parse()
-                .expect(&format!("Failed to parse GPU platform in string {:?}", s));
-            let device = parts
-                .next()
-                .expect(&format!("GPU string {:?} must have at least one colon", s))
-                .parse()
-                .expect(&format!("Failed to parse GPU device in string {:?}", s));
-            let threads = parts
-                .next()
-                .unwrap_or("1048576")
-                .parse()
-                .expect(&format!("Failed to parse GPU threads in string {:?}", s));
-            if parts.next().is_some() {
-                panic!("Too many colons in GPU string {:?}", s);
-            }
-            Gpu::new(platform, device, threads, gpu_local_work_size)
-                .expect(&format!("Failed to create GPU from string {:?}", s))
+        .filter_map(|s| {
+            parse_gpu(s, gpu_local_work_size, &mut seen_gpus, allow_duplicate_gpu, &devices)
+                .map(|spec| (s, spec))
+                .map_err(|err| gpu_errors.push(err))
+                .ok()
         })
         .collect();
+    if !gpu_errors.is_empty() {
+        for err in gpu_errors {
+            eprintln!("{}", err);
+        }
+        process::exit(1);
+    }
+    // A device failing to initialize, e.g. out of memory, leaves the others usable
+    let require_all_gpus = args.is_present("require_all_gpus");
+    let mut gpus = Vec::new();
+    let mut started_gpus = Vec::new();
+    for (s, (platform, device, threads, local_work_size)) in gpu_specs {
+        match Gpu::new(platform, device, threads, local_work_size, &hasher) {
+            Ok(gpu) => {
+                gpus.push(gpu);
+                started_gpus.push(s);
+            }
+            Err(err) if require_all_gpus => {
+                eprintln!("Failed to create GPU from string {:?}: {}", s, err);
+                process::exit(1);
+            }
+            Err(err) => error!("Failed to create GPU from string {:?}, skipping it: {}", s, err),
+        }
+    }
 
     let n_workers = gpus.len() + cpu_threads;
     if n_workers == 0 {
         eprintln!("No workers specified. Please use the --gpu or --cpu-threads flags.\nUse --help for more options.");
         process::exit(1);
     }
+    let metrics = Arc::new(metrics::Metrics::default());
+    let phase_metrics = args.is_present("phase_metrics");
     let work_state = Arc::new((Mutex::new(WorkState::default()), Condvar::new()));
     {
         let mut state = work_state.0.lock();
+        state.phase_metrics = Some(metrics.clone()).filter(|_| phase_metrics);
         state.task_complete.store(true, atomic::Ordering::Relaxed);
         state.random_mode = random_mode;
+        state.fair_queue = args.is_present("fair_queue");
+        state.all_workers = args.is_present("all_workers");
+        state.track_progress = args.is_present("track_progress");
+        state.n_workers = n_workers;
+        state.n_fallback_workers = fallback_cpu_threads;
     }
+    let throughput = Arc::new(Throughput::default());
+    let gpu_monitors: Vec<GpuMonitor> = gpus.iter().map(Gpu::monitor).collect();
+    work_state.0.lock().gpu_health = gpu_monitors.iter().map(GpuMonitor::shared_health).collect();
+    let readiness = Arc::new(Readiness::new(gpus.len()));
     let mut worker_handles = Vec::new();
-    for _ in 0..cpu_threads {
-        let work_state = work_state.clone();
-        let mut rng =
-            XorShiftRng::from_rng(rand::thread_rng()).expect("Failed to create XorShiftRng");
-        let mut root = [0u8; 32];
-        let mut threshold = [0u8; 32];
-        let mut task_complete = Arc::new(AtomicBool::new(true));
-        let handle = thread::spawn(move || loop {
-            if task_complete.load(atomic::Ordering::Relaxed) {
-                let mut state = work_state.0.lock();
-                while state.callback.is_none() {
-                    work_state.1.wait(&mut state);
-                }
-                root = state.root;
-                threshold = state.threshold;
-                task_complete = state.task_complete.clone();
-            }
-            let mut out: [u8; 8] = rng.gen();
-            for _ in 0..(1 << 18) {
-                if work_valid(root, out, threshold).0 {
-                    let mut state = work_state.0.lock();
-                    if root == state.root {
-                        if let Some(callback) = state.callback.take() {
-                            let _ = callback.send(Ok(out));
-                            state.set_task(&work_state.1);
-                        }
-                    }
-                    break;
-                }
-                for byte in out.iter_mut() {
-                    *byte = byte.wrapping_add(1);
-                    if *byte != 0 {
-                        // We did not overflow
-                        break;
-                    }
-                }
-            }
-        });
+    let cpu_config = CpuConfig {
+        batch_size: cpu_batch_size,
+        double_check,
+        max_attempts,
+        numa_node,
+    };
+    for cpu_i in 0..cpu_threads + fallback_cpu_threads {
+        let fallback = cpu_i >= cpu_threads;
+        let name = if fallback {
+            format!("Fallback CPU thread {}", cpu_i - cpu_threads)
+        } else {
+            format!("CPU thread {}", cpu_i)
+        };
+        let handle = spawn_cpu_worker(cpu_i, fallback, name, &work_state, &throughput, &hasher, &cpu_config);
         worker_handles.push(handle.thread().clone());
     }
     for (gpu_i, mut gpu) in gpus.into_iter().enumerate() {
@@ -603,11 +3946,29 @@ async fn main() {
             XorShiftRng::from_rng(rand::thread_rng()).expect("Failed to create XorShiftRng");
         let mut root = [0u8; 32];
         let mut threshold = [0u8; 32];
+        let crash_state = work_state.clone();
         let work_state = work_state.clone();
+        let throughput = throughput.clone();
+        let hasher = hasher.clone();
         let mut task_complete = Arc::new(AtomicBool::new(true));
+        let mut attempts = Arc::new(AtomicU64::new(0));
         let mut consecutive_gpu_errors = 0;
         let mut consecutive_gpu_invalid_work_errors = 0;
-        let handle = thread::spawn(move || loop {
+        let mut total_gpu_errors = 0;
+        let readiness = readiness.clone();
+        let mut initialized = false;
+        let worker = move || loop {
+            if !initialized {
+                initialized = true;
+                // A first dispatch shows the GPU works, and gets the driver's lazy setup out of the way
+                match gpu.warmup() {
+                    Ok(()) => readiness.gpu_ready(),
+                    Err(err) => error!(
+                        "GPU {} failed its first dispatch, /ready will keep failing: {:?}",
+                        gpu_i, err
+                    ),
+                }
+            }
             if failed || task_complete.load(atomic::Ordering::Relaxed) {
                 let mut state = work_state.0.lock();
                 if root != state.root {
@@ -615,102 +3976,1066 @@ async fn main() {
                 }
                 if failed {
                     state.unsuccessful_workers += 1;
-                    if state.unsuccessful_workers == n_workers {
-                        if let Some(callback) = state.callback.take() {
-                            let _ = callback.send(Err(WorkError::Errored));
-                            state.set_task(&work_state.1);
+                    state.fail_if_no_workers(&work_state.1);
+                    park(&mut state, &work_state.1, Worker::Gpu(gpu_i), None);
+                    // Not counted while waiting out a task pinned to another worker
+                    state.unsuccessful_workers -= 1;
+                }
+                while !state.runs_task(Worker::Gpu(gpu_i)) {
+                    if park(&mut state, &work_state.1, Worker::Gpu(gpu_i), warmup_interval)
+                        && !state.runs_task(Worker::Gpu(gpu_i))
+                    {
+                        // Don't hold the lock while the GPU is busy, a real task takes priority
+                        if let Err(err) = MutexGuard::unlocked(&mut state, || gpu.warmup()) {
+                            warn!("Failed to warm up GPU {}: {:?}", gpu_i, err);
                         }
                     }
-                    work_state.1.wait(&mut state);
-                }
-                while state.callback.is_none() {
-                    work_state.1.wait(&mut state);
                 }
                 root = state.root;
                 threshold = state.threshold;
                 task_complete = state.task_complete.clone();
-                if failed {
-                    state.unsuccessful_workers -= 1;
-                }
-                if let Err(err) = gpu.set_task(&root, &threshold) {
-                    eprintln!(
+                attempts = state.attempts.clone();
+                state.record_assigned();
+                // Each GPU has its own seed, so GPUs on the same task search different ranges
+                if let Err(err) = gpu.set_task(&root, &threshold, rng.gen()) {
+                    error!(
                         "Failed to set GPU {}'s task, abandoning it for this work: {:?}",
                         gpu_i, err,
                     );
+                    if state.n_fallback_workers > 0 && !state.fallback && state.task_worker.is_none() {
+                        warn!(
+                            "Engaging {} fallback CPU threads for {}",
+                            state.n_fallback_workers,
+                            hex::encode_upper(root),
+                        );
+                        state.fallback = true;
+                        work_state.1.notify_all();
+                    }
                     failed = true;
                     continue;
                 }
                 failed = false;
                 consecutive_gpu_errors = 0;
             }
-            let attempt = rng.gen();
             let mut out = [0u8; 8];
-            match gpu.run(&mut out, attempt) {
+            // Stops early on a result or an error, so the counters below see each of them
+            let mut dispatches = 0;
+            let result = loop {
+                dispatches += 1;
+                match gpu.run(&mut out) {
+                    Ok(false) if dispatches < gpu_dispatch_multiplier => {}
+                    result => break result,
+                }
+            };
+            let tried = dispatches * gpu.threads() as u64;
+            // The nonces of the dispatches before the last one, which found nothing
+            let searched = tried - gpu.threads() as u64;
+            match result {
                 Ok(true) => {
-                    if work_valid(root, out, threshold).0 {
+                    throughput.add(tried);
+                    gpu.health().throughput().add(tried);
+                    let mut valid = hasher.work_valid(root, out, threshold).0;
+                    if valid && double_check && !hasher.reference_valid(root, out, threshold) {
+                        error!(
+                            "Work {} from GPU {} for {} doesn't pass the double check, discarding it",
+                            hex::encode(out),
+                            gpu_i,
+                            hex::encode_upper(root),
+                        );
+                        valid = false;
+                    }
+                    gpu.health().record(valid);
+                    if valid {
                         let mut state = work_state.0.lock();
                         if root == state.root {
-                            if let Some(callback) = state.callback.take() {
-                                let _ = callback.send(Ok(out));
-                                state.set_task(&work_state.1);
-                            }
+                            state.solve(out, Worker::Gpu(gpu_i), tried, &work_state.1);
                         }
                         consecutive_gpu_errors = 0;
                         consecutive_gpu_invalid_work_errors = 0;
                     } else {
-                        eprintln!(
+                        count_attempts(&work_state, &attempts, searched, max_attempts);
+                        warn!(
                             "GPU {} returned invalid work {} for root {}",
                             gpu_i,
-                            hex::encode(&out),
-                            hex::encode_upper(&root),
+                            hex::encode(out),
+                            hex::encode_upper(root),
                         );
-                        if consecutive_gpu_invalid_work_errors >= 3 {
-                            eprintln!("GPU {} returned invalid work 3 consecutive times, abandoning it for this work", gpu_i);
+                        total_gpu_errors += 1;
+                        if consecutive_gpu_invalid_work_errors >= gpu_invalid_tolerance {
+                            error!(
+                                "GPU {} returned invalid work {} consecutive times, abandoning it for this work",
+                                gpu_i, gpu_invalid_tolerance,
+                            );
                             failed = true;
                         } else {
                             consecutive_gpu_errors += 1;
                             consecutive_gpu_invalid_work_errors += 1;
                         }
+                        let invalid_rate = gpu.health().invalid_rate();
+                        if let (Some(max_rate), Some(rate)) = (reject_flaky_gpu, invalid_rate) {
+                            if rate > max_rate {
+                                error!(
+                                    "GPU {} returned invalid work for {:.1}% of its results, disabling it",
+                                    gpu_i,
+                                    rate * 100.0,
+                                );
+                                gpu.health().disable();
+                                let mut state = work_state.0.lock();
+                                state.mark_dead(Worker::Gpu(gpu_i));
+                                state.fail_if_no_workers(&work_state.1);
+                                return;
+                            }
+                        }
                     }
                 }
                 Ok(false) => {
                     consecutive_gpu_errors = 0;
+                    throughput.add(tried);
+                    gpu.health().throughput().add(tried);
+                    count_attempts(&work_state, &attempts, tried, max_attempts);
                 }
                 Err(err) => {
-                    eprintln!("Error computing work on GPU {}: {:?}", gpu_i, err);
+                    throughput.add(searched);
+                    gpu.health().throughput().add(searched);
+                    count_attempts(&work_state, &attempts, searched, max_attempts);
+                    error!("Error computing work on GPU {}: {:?}", gpu_i, err);
                     if let Err(err) = gpu.reset_bufs() {
-                        eprintln!(
+                        error!(
                             "Failed to reset GPU {}'s buffers, abandoning it for this work: {:?}",
                             gpu_i, err,
                         );
                         failed = true;
                     }
                     consecutive_gpu_errors += 1;
+                    total_gpu_errors += 1;
                 }
             }
-            if consecutive_gpu_errors >= 3 {
-                eprintln!(
-                    "3 consecutive GPU {} errors, abandoning it for this work",
-                    gpu_i,
+            if gpu_max_errors.is_some_and(|max| total_gpu_errors >= max) {
+                error!(
+                    "GPU {} had {} errors and invalid results in total, disabling it",
+                    gpu_i, total_gpu_errors,
+                );
+                gpu.health().disable();
+                let mut state = work_state.0.lock();
+                state.mark_dead(Worker::Gpu(gpu_i));
+                state.fail_if_no_workers(&work_state.1);
+                return;
+            }
+            if consecutive_gpu_errors >= gpu_error_tolerance {
+                error!(
+                    "{} consecutive GPU {} errors, abandoning it for this work",
+                    consecutive_gpu_errors, gpu_i,
                 );
                 failed = true;
             }
+        };
+        let handle = thread::spawn(move || {
+            run_worker(format!("GPU {}", gpu_i), Worker::Gpu(gpu_i), false, crash_state, worker)
         });
         worker_handles.push(handle.thread().clone());
     }
 
+    // The API key is left out, as the dump may end up in a ticket or a chat
+    let config = {
+        let numeric_json = args.is_present("numeric_json");
+        let number = |n: u64| if numeric_json { json!(n) } else { json!(n.to_string()) };
+        let secs = |d: Option<Duration>| d.map(|d| number(d.as_secs()));
+        let flags: serde_json::Map<String, Value> = CONFIG_FLAGS
+            .iter()
+            .map(|&flag| (flag.to_string(), json!(args.is_present(flag))))
+            .collect();
+        json!({
+            "listen": listen_addr.to_string(),
+            "rpc_path": &*rpc_path,
+            "cpu_threads": number(cpu_threads as u64),
+            "fallback_cpu_threads": number(fallback_cpu_threads as u64),
+            "gpus": started_gpus,
+            "base_threshold": hex::encode(base_threshold),
+            "min_threshold": min_threshold.map(hex::encode),
+            "max_threshold": max_threshold.map(hex::encode),
+            "difficulty_names": difficulty_names
+                .iter()
+                .map(|(name, threshold)| (name.clone(), json!(hex::encode(threshold))))
+                .collect::<serde_json::Map<String, Value>>(),
+            "max_attempts": max_attempts.map(number),
+            "cpu_batch_size": number(cpu_batch_size),
+            "gpu_dispatch_multiplier": number(gpu_dispatch_multiplier),
+            "keep_alive_timeout": secs(keep_alive_timeout),
+            "io_timeout": secs(io_timeout),
+            "slow_request_ms": slow_request.map(|d| number(d.as_millis() as u64)),
+            "work_timeout": secs(work_timeout),
+            "max_connections": args.value_of("max_connections").map(|s| number(s.parse().unwrap())),
+            "max_in_flight": number(max_in_flight as u64),
+            "max_request_rate_global": request_rate.as_ref().map(|rate| number(rate.limit())),
+            "max_benchmark_count": number(max_benchmark_count),
+            "result_cache_size": number(result_cache_size as u64),
+            "result_ttl": secs(result_ttl),
+            "upstream": upstream.as_ref().map(|upstream| upstream.uri().to_string()),
+            "upstream_timeout": number(upstream_timeout),
+            "peers": peers.iter().map(|peer| peer.uri().to_string()).collect::<Vec<_>>(),
+            "webhook": webhook.as_ref().map(|webhook| webhook.uri().to_string()),
+            "api_key": args.value_of("api_key").map(|_| "<redacted>"),
+            "auth_actions": auth_actions.as_deref(),
+            "disabled_actions": &*disabled_actions,
+            "flags": flags,
+        })
+    };
     let service = RpcService {
         work_state: work_state.clone(),
+        hasher,
+        connections: Arc::new(AtomicUsize::new(0)),
+        rpc_path,
+        api_key: args.value_of("api_key").map(Arc::from),
+        auth_actions,
+        stats: Arc::new(Mutex::new(Stats::new())),
+        base_threshold,
+        difficulty_names: Arc::new(difficulty_names),
+        default_endian: if args.is_present("raw_work") {
+            Endian::Little
+        } else {
+            Endian::Big
+        },
+        gpus: Arc::new(gpu_monitors),
+        cpu_threads,
+        allow_remote_shutdown: args.is_present("allow_remote_shutdown"),
+        shutdown: Arc::new(Notify::new()),
+        throughput: throughput.clone(),
+        compression: args.is_present("enable_compression"),
+        min_threshold,
+        max_threshold,
+        jsonrpc: args.is_present("jsonrpc"),
+        numeric_json: args.is_present("numeric_json"),
+        strict_content_type: args.is_present("strict_content_type"),
+        strict_http_status: args.is_present("strict_http_status"),
+        precomputed: Arc::new(precompute::Cache::new(result_cache_size, result_ttl)),
+        max_benchmark_count,
+        metrics,
+        buffers: Arc::new(buffers::BufferPool::new(response_buffers)),
+        phase_metrics,
+        io_timeout,
+        slow_request,
+        work_timeout,
+        readiness,
+        max_in_flight,
+        disabled_actions,
+        request_rate,
+        upstream,
+        peers,
+        webhook,
+        config: Arc::new(config),
+        activity: Arc::new(Activity {
+            requests: AtomicUsize::new(0),
+            last_request: Mutex::new(Instant::now()),
+        }),
     };
-    let make_service = hyper::service::make_service_fn(|_| {
-        let service = service.clone();
-        async move {
-            Ok::<_, Infallible>(hyper::service::service_fn(move |req| {
-                service.clone().handle_request(req)
-            }))
+    if args.is_present("self_test") {
+        let passed = service.self_test().await;
+        process::exit(if passed { 0 } else { 1 });
+    }
+    if args.is_present("startup_bench") {
+        service.startup_bench().await;
+    }
+    if let Some(path) = precompute_file {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        precompute::spawn_feed(path, base_threshold, sender);
+        tokio::spawn(service.clone().precompute(receiver));
+    }
+    if let Some(period) = heartbeat {
+        tokio::spawn(service.clone().heartbeat(period));
+    }
+    if let Some(after) = idle_shutdown {
+        tokio::spawn(service.clone().idle_shutdown(after));
+    }
+    let gpus = service.gpus.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HASHRATE_INTERVAL);
+        let mut last = Instant::now();
+        loop {
+            interval.tick().await;
+            let elapsed = last.elapsed();
+            last = Instant::now();
+            throughput.update(elapsed);
+            for gpu in gpus.iter() {
+                gpu.health().throughput().update(elapsed);
+            }
         }
     });
-    let server = Server::bind(&listen_addr).serve(make_service);
-    println!("Ready to receive requests on {}", listen_addr);
-    server.await.expect("Failed to serve requests");
+    let listener = bind_listener_retrying(listen_addr, listen_backlog, Duration::from_secs(bind_retry))
+        .await
+        .unwrap_or_else(|err| {
+            error!("Failed to bind to {}: {}", listen_addr, err);
+            process::exit(1);
+        });
+    // With port 0 the OS picks one
+    let local_addr = listener.local_addr().unwrap_or(listen_addr);
+    let pidfile = args.value_of("pidfile").map(PathBuf::from);
+    if let Some(pidfile) = &pidfile {
+        write_pidfile(pidfile);
+    }
+    if let Some(port_file) = args.value_of("port_file") {
+        write_port_file(Path::new(port_file), local_addr.port());
+    }
+    info!("Ready to receive requests on {}", local_addr);
+    let config = ServerConfig {
+        connection_limit,
+        keep_alive_timeout,
+        io_timeout,
+        http2: args.is_present("http2"),
+    };
+    run_server(service, listener, config)
+        .await
+        .expect("Failed to serve requests");
+    if let Some(pidfile) = &pidfile {
+        if let Err(err) = fs::remove_file(pidfile) {
+            warn!("Failed to remove pidfile {}: {}", pidfile.display(), err);
+        }
+    }
+    info!("Shut down");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    /// The root of the known answer, `718cc2…`
+    const ROOT: &str = "718cc2121c3e641059bc1c2cfc45666c99e8ae922f7a807b7d07b62c995d79e2";
+
+    /// Met by one nonce in 256, so a single CPU thread finds work in a few milliseconds
+    const EASY_THRESHOLD: &str = "ff00000000000000000000000000000000000000000000000000000000000000";
+
+    /// Met by no nonce, to keep a task running until it's cancelled
+    const UNREACHABLE_THRESHOLD: &str = "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
+
+    fn cpu_config() -> CpuConfig {
+        CpuConfig {
+            batch_size: 1024,
+            double_check: false,
+            max_attempts: None,
+            numa_node: None,
+        }
+    }
+
+    /// A service like `main` sets up with the default options, with `cpu_threads` CPU threads
+    /// running `config` and no GPUs
+    fn test_service(cpu_threads: usize, config: CpuConfig) -> RpcService {
+        let hasher = Arc::new(WorkHasher::new(32, &[], &[]).unwrap());
+        let work_state = Arc::new((Mutex::new(WorkState::default()), Condvar::new()));
+        {
+            let mut state = work_state.0.lock();
+            state.task_complete.store(true, atomic::Ordering::Relaxed);
+            state.n_workers = cpu_threads;
+        }
+        let throughput = Arc::new(Throughput::default());
+        for cpu_i in 0..cpu_threads {
+            let name = format!("CPU thread {}", cpu_i);
+            spawn_cpu_worker(cpu_i, false, name, &work_state, &throughput, &hasher, &config);
+        }
+        RpcService {
+            work_state,
+            hasher,
+            connections: Arc::new(AtomicUsize::new(0)),
+            rpc_path: "/".into(),
+            api_key: None,
+            auth_actions: None,
+            stats: Arc::new(Mutex::new(Stats::new())),
+            base_threshold: difficulty::BASE_THRESHOLD,
+            difficulty_names: Arc::new(HashMap::new()),
+            default_endian: Endian::Big,
+            gpus: Arc::new(Vec::new()),
+            cpu_threads,
+            allow_remote_shutdown: false,
+            shutdown: Arc::new(Notify::new()),
+            throughput,
+            compression: false,
+            min_threshold: None,
+            max_threshold: None,
+            jsonrpc: false,
+            numeric_json: false,
+            strict_content_type: false,
+            strict_http_status: false,
+            precomputed: Arc::new(precompute::Cache::new(100_000, None)),
+            max_benchmark_count: 10_000,
+            metrics: Arc::new(metrics::Metrics::default()),
+            buffers: Arc::new(buffers::BufferPool::new(64)),
+            phase_metrics: false,
+            io_timeout: None,
+            slow_request: None,
+            work_timeout: None,
+            readiness: Arc::new(Readiness::new(0)),
+            disabled_actions: Arc::from(Vec::new()),
+            max_in_flight: 64,
+            request_rate: None,
+            upstream: None,
+            peers: Arc::from(Vec::new()),
+            webhook: None,
+            config: Arc::new(json!({})),
+            activity: Arc::new(Activity {
+                requests: AtomicUsize::new(0),
+                last_request: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    /// Serves `service` on an ephemeral port of 127.0.0.1 until it's shut down, returning the
+    /// base URL and the server's task
+    async fn serve(service: RpcService) -> (String, tokio::task::JoinHandle<hyper::Result<()>>) {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 128).unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let config = ServerConfig {
+            connection_limit: None,
+            keep_alive_timeout: None,
+            io_timeout: None,
+            http2: false,
+        };
+        (url, tokio::spawn(run_server(service, listener, config)))
+    }
+
+    /// Sends `body` with `method` and returns the response with its body parsed
+    async fn request(method: hyper::Method, url: &str, body: &str) -> (Response<()>, Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(url)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let resp = hyper::Client::new().request(req).await.unwrap();
+        let (parts, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (Response::from_parts(parts, ()), serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn post(url: &str, body: Value) -> (StatusCode, Value) {
+        let (resp, body) = request(hyper::Method::POST, url, &body.to_string()).await;
+        (resp.status(), body)
+    }
+
+    async fn get_text(url: &str) -> (StatusCode, String) {
+        let resp = hyper::Client::new().get(url.parse().unwrap()).await.unwrap();
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Sends a request on a connection of its own, which is closed when the
+    /// returned stream is dropped
+    fn post_raw(url: &str, body: Value) -> std::net::TcpStream {
+        let body = body.to_string();
+        let mut stream = std::net::TcpStream::connect(url.trim_start_matches("http://")).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        stream
+    }
+
+
+    /// Waits for `condition` to hold, failing the test after 10 seconds
+    async fn wait_for(mut condition: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(10), "Timed out waiting for a condition");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Queues work for `root` on `work_state`, starting it if the workers are idle
+    fn queue_work(work_state: &(Mutex<WorkState>, Condvar), root: [u8; 32]) -> oneshot::Receiver<WorkResult> {
+        queue_work_from(work_state, root, None)
+    }
+
+    /// Like `queue_work`, for a request from `client`
+    fn queue_work_from(
+        work_state: &(Mutex<WorkState>, Condvar),
+        root: [u8; 32],
+        client: Option<IpAddr>,
+    ) -> oneshot::Receiver<WorkResult> {
+        let (callback, result) = oneshot::channel();
+        let mut state = work_state.0.lock();
+        state.enqueue(QueuedWork {
+            root,
+            threshold: [0xff; 32],
+            callback,
+            client,
+            allow_partial: false,
+            worker: None,
+            queued_at: Instant::now(),
+        });
+        state.set_task(&work_state.1);
+        result
+    }
+
+    #[test]
+    fn work_fails_once_the_only_cpu_thread_crashes() {
+        let work_state = Arc::new((Mutex::new(WorkState::default()), Condvar::new()));
+        work_state.0.lock().n_workers = 1;
+        let mut current = queue_work(&work_state, [1; 32]);
+        let mut queued = queue_work(&work_state, [2; 32]);
+        run_worker("CPU thread 0".to_string(), Worker::Cpu(0), false, work_state.clone(), || {
+            panic!("Crashing on purpose")
+        });
+        assert!(matches!(current.try_recv(), Ok(Some(Err(WorkError::Errored)))));
+        // Nothing is left to generate the queued work either
+        assert!(matches!(queued.try_recv(), Ok(Some(Err(WorkError::Errored)))));
+        let mut late = queue_work(&work_state, [3; 32]);
+        assert!(matches!(late.try_recv(), Ok(Some(Err(WorkError::Errored)))));
+    }
+
+    #[test]
+    fn cpu_threads_finish_the_work_of_a_failed_gpu() {
+        let work_state = Arc::new((Mutex::new(WorkState::default()), Condvar::new()));
+        work_state.0.lock().n_workers = 2;
+        let mut result = queue_work(&work_state, [1; 32]);
+        {
+            // What a GPU does once it abandons the work
+            let mut state = work_state.0.lock();
+            state.unsuccessful_workers += 1;
+            state.fail_if_no_workers(&work_state.1);
+        }
+        assert!(matches!(result.try_recv(), Ok(None)));
+        work_state.0.lock().solve([7; 8], Worker::Cpu(0), 1, &work_state.1);
+        assert!(matches!(result.try_recv(), Ok(Some(Ok(([7, 7, 7, 7, 7, 7, 7, 7], _))))));
+    }
+
+    #[tokio::test]
+    async fn generated_work_validates() {
+        let (url, _) = serve(test_service(1, cpu_config())).await;
+        let (status, generated) = post(
+            &url,
+            json!({ "action": "work_generate", "hash": ROOT, "threshold": EASY_THRESHOLD }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(generated["target_threshold"], EASY_THRESHOLD);
+        let (status, validated) = post(
+            &url,
+            json!({
+                "action": "work_validate",
+                "hash": ROOT,
+                "work": generated["work"],
+                "threshold": EASY_THRESHOLD,
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(validated["valid"], true);
+        assert_eq!(validated["threshold"], generated["threshold"]);
+    }
+
+    #[tokio::test]
+    async fn work_cancel_answers_the_pending_request() {
+        let service = test_service(1, cpu_config());
+        let (url, _) = serve(service.clone()).await;
+        let generate = tokio::spawn({
+            let url = url.clone();
+            let body = json!({ "action": "work_generate", "hash": ROOT, "threshold": UNREACHABLE_THRESHOLD });
+            async move { post(&url, body).await }
+        });
+        wait_for(|| service.work_state.0.lock().callback.is_some()).await;
+        let (status, cancelled) = post(&url, json!({ "action": "work_cancel", "hash": ROOT })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(cancelled["cancelled"], "1");
+        let (status, resp) = generate.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(resp["code"], "CANCELLED");
+        assert!(service.work_state.0.lock().callback.is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_rejected() {
+        let (url, _) = serve(test_service(1, cpu_config())).await;
+        for (body, code) in [
+            ("", "EMPTY_BODY"),
+            ("{\"action\": ", "INVALID_JSON"),
+            ("{}", "MISSING_FIELD"),
+            ("{\"action\": \"work_generat\"}", "UNKNOWN_COMMAND"),
+            ("{\"action\": \"work_generate\"}", "MISSING_FIELD"),
+            ("{\"action\": \"work_generate\", \"hash\": \"718cc2\"}", "BAD_HASH"),
+            ("{\"action\": \"work_generate\", \"hash\": \"zz\"}", "BAD_HASH"),
+            (
+                "{\"action\": \"work_validate\", \"hash\": \"718cc2121c3e641059bc1c2cfc45666c99e8ae922f7a807b7d07b62c995d79e2\", \"work\": \"2bf29ef00786a6bc00\", \"threshold\": \"ff\"}",
+                "BAD_WORK",
+            ),
+        ] {
+            let (resp, err) = request(hyper::Method::POST, &url, body).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+            assert_eq!(err["code"], code, "{}", body);
+        }
+        let (_, err) = request(hyper::Method::POST, &url, "{\"action\": \"work_generate\", \"hash\": \"zz\"}").await;
+        assert_eq!(err["field"], "hash");
+        assert_eq!(err["got"], "zz");
+        let (_, err) = request(hyper::Method::POST, &url, "{\"action\": \"work_generat\"}").await;
+        assert!(err["hint"].as_str().unwrap().ends_with("queue_dump, config_dump"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn metrics_match_status() {
+        let service = test_service(1, cpu_config());
+        service.throughput.add(12_345);
+        service.throughput.update(Duration::from_secs(1));
+        let (url, _) = serve(service).await;
+        let (_, status) = post(&url, json!({ "action": "status" })).await;
+        post(&url, json!({ "action": "nope" })).await;
+        let (code, metrics) = get_text(&format!("{}{}", url, METRICS_PATH)).await;
+        assert_eq!(code, StatusCode::OK);
+        let hashrate: f64 = metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("vite_work_server_hashrate "))
+            .expect("No hashrate in /metrics")
+            .parse()
+            .unwrap();
+        assert!(hashrate > 0.0);
+        assert_eq!(status["hashrate"], (hashrate.round() as u64).to_string());
+        assert!(metrics.contains("vite_work_server_request_duration_seconds_count{action=\"status\",status=\"200\"} 1\n"));
+        assert!(metrics.contains("vite_work_server_request_duration_seconds_count{action=\"unknown\",status=\"400\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn allow_partial_needs_a_cpu_thread() {
+        let service = test_service(1, cpu_config());
+        let (url, _) = serve(service.clone()).await;
+        let partial = json!({
+            "action": "work_generate",
+            "hash": ROOT,
+            "threshold": UNREACHABLE_THRESHOLD,
+            "allow_partial": true,
+        });
+        service.work_state.0.lock().mark_dead(Worker::Cpu(0));
+        let (status, resp) = post(&url, partial).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(resp["code"], "PARTIAL_UNSUPPORTED");
+        let (_, resp) = post(
+            &url,
+            json!({ "action": "work_generate", "hash": ROOT, "threshold": UNREACHABLE_THRESHOLD }),
+        )
+        .await;
+        assert_eq!(resp["code"], "GENERATION_FAILED");
+    }
+
+    fn parse_work(work: Value, endian: Endian) -> [u8; 8] {
+        RpcService::parse_work_json(&json!({ "work": work }), endian).unwrap()
+    }
+
+    fn root() -> [u8; 32] {
+        hex::decode(ROOT).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn work_is_a_big_endian_nonce() {
+        // The work of the `work_validate` example in the Nano RPC documentation
+        let work = parse_work(json!("2bf29ef00786a6bc"), Endian::Big);
+        assert_eq!(work, 0x2bf29ef00786a6bc_u64.to_le_bytes());
+        assert_eq!(work, SELF_TEST_KNOWN_WORK);
+        assert_eq!(parse_work(json!("bca68607f09ef22b"), Endian::Little), work);
+        // Short work is a smaller number, or the first bytes hashed
+        assert_eq!(parse_work(json!("86a6bc"), Endian::Big), 0x86a6bc_u64.to_le_bytes());
+        assert_eq!(parse_work(json!("bca686"), Endian::Little), [0xbc, 0xa6, 0x86, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn known_answer() {
+        let work = 0x2bf29ef00786a6bc_u64.to_le_bytes();
+        // Both checked against Python's hashlib.blake2b
+        let hasher = WorkHasher::new(32, &[], &[]).unwrap();
+        let value = hasher.work_value(root(), work);
+        assert_eq!(
+            hex::encode(value),
+            "08d8374b7c88313fe718e72f5fe257b031e2a0ff221b925f816defe17f852ee5"
+        );
+        assert_eq!(value, SELF_TEST_KNOWN_VALUE);
+        assert!(hasher.reference_valid(root(), work, value));
+        // Nano hashes to 8 bytes, read as a little endian difficulty
+        let nano = WorkHasher::new(8, &[], &[]).unwrap();
+        let value = nano.work_value(root(), work);
+        assert_eq!(u64::from_le_bytes(value[..8].try_into().unwrap()), 0xffffffd21c3933f4);
+        assert_eq!(value[8..], [0; 24]);
+    }
+
+    #[tokio::test]
+    async fn generated_work_validates_in_both_byte_orders() {
+        let (url, _) = serve(test_service(1, cpu_config())).await;
+        for endian in ["big", "little"] {
+            let (_, generated) = post(
+                &url,
+                json!({ "action": "work_generate", "hash": ROOT, "threshold": EASY_THRESHOLD, "endian": endian }),
+            )
+            .await;
+            let work = generated["work"].as_str().unwrap();
+            let (_, validated) = post(
+                &url,
+                json!({
+                    "action": "work_validate",
+                    "hash": ROOT,
+                    "work": work,
+                    "threshold": EASY_THRESHOLD,
+                    "endian": endian,
+                }),
+            )
+            .await;
+            assert_eq!(validated["valid"], true, "{} endian work {}", endian, work);
+            // The threshold is that of the hashed bytes, the big endian work reversed
+            let mut hashed = hex::decode(work).unwrap();
+            if endian == "big" {
+                hashed.reverse();
+            }
+            hashed.extend_from_slice(&root());
+            let mut value = [0u8; 32];
+            reference::blake2b(&mut value, &[], &[], &hashed);
+            assert_eq!(generated["threshold"], hex::encode(value));
+            assert_eq!(validated["threshold"], hex::encode(value));
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_answers_every_request() {
+        let service = test_service(2, cpu_config());
+        let (url, server) = serve(service.clone()).await;
+        let requests: Vec<_> = (0..32)
+            .map(|i| {
+                let url = url.clone();
+                // Half of them are found quickly, the others run until cancelled
+                let threshold = if i % 2 == 0 { EASY_THRESHOLD } else { UNREACHABLE_THRESHOLD };
+                let body = json!({
+                    "action": "work_generate",
+                    "hash": format!("{:064x}", i + 1),
+                    "threshold": threshold,
+                });
+                tokio::spawn(async move { post(&url, body).await })
+            })
+            .collect();
+        wait_for(|| service.stats.lock().work_requests == 32).await;
+        service.shutdown.notify_one();
+        let mut shut_down = 0;
+        for request in requests {
+            let (status, resp) = request.await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            if resp.get("work").is_none() {
+                assert_eq!(resp["code"], "SHUTTING_DOWN", "{}", resp);
+                shut_down += 1;
+            }
+        }
+        assert!(shut_down >= 16);
+        server.await.unwrap().unwrap();
+        let state = service.work_state.0.lock();
+        assert!(state.callback.is_none() && state.future_work.is_empty());
+    }
+
+    #[tokio::test]
+    async fn exhausted_attempts_only_return_partial_work_when_allowed() {
+        let service = test_service(
+            1,
+            CpuConfig {
+                max_attempts: Some(4096),
+                ..cpu_config()
+            },
+        );
+        // Tracks the best work of every task, including those not asking for it
+        service.work_state.0.lock().track_progress = true;
+        let (url, _) = serve(service).await;
+        let generate = json!({ "action": "work_generate", "hash": ROOT, "threshold": UNREACHABLE_THRESHOLD });
+        let (_, resp) = post(&url, generate.clone()).await;
+        assert_eq!(resp["code"], "EXHAUSTED_ATTEMPTS");
+        assert!(resp.get("work").is_none());
+        let mut partial = generate;
+        partial["allow_partial"] = json!(true);
+        let (_, resp) = post(&url, partial).await;
+        assert_eq!(resp["incomplete"], true);
+        assert!(resp["work"].is_string());
+    }
+
+    #[test]
+    fn numeric_work_is_the_big_endian_hex() {
+        for n in [0, 1, 0x86a6bc, 0x2bf29ef00786a6bc, u64::MAX] {
+            let numeric = parse_work(json!(n), Endian::Big);
+            assert_eq!(numeric, parse_work(json!(format!("{:016x}", n)), Endian::Big), "{:x}", n);
+            assert_eq!(numeric, parse_work(json!(hex::encode(n.to_le_bytes())), Endian::Little), "{:x}", n);
+            // The endian only applies to hex
+            assert_eq!(parse_work(json!(n), Endian::Little), numeric, "{:x}", n);
+            // And back, as a response writes it
+            let mut work = numeric;
+            work.reverse();
+            assert_eq!(hex::encode(work), format!("{:016x}", n));
+            assert_eq!(u64::from_le_bytes(numeric), n);
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnecting_cancels_the_work() {
+        let service = test_service(1, cpu_config());
+        let (url, _) = serve(service.clone()).await;
+        let generate = |root: [u8; 32]| {
+            json!({ "action": "work_generate", "hash": hex::encode(root), "threshold": UNREACHABLE_THRESHOLD })
+        };
+        let running = post_raw(&url, generate([1; 32]));
+        wait_for(|| service.work_state.0.lock().callback.is_some()).await;
+        let queued = post_raw(&url, generate([2; 32]));
+        wait_for(|| service.work_state.0.lock().future_work.len() == 1).await;
+        drop(queued);
+        wait_for(|| service.work_state.0.lock().future_work.is_empty()).await;
+        {
+            let state = service.work_state.0.lock();
+            assert_eq!(state.root, [1; 32]);
+            assert!(state.callback.is_some());
+        }
+        drop(running);
+        wait_for(|| service.work_state.0.lock().callback.is_none()).await;
+        // The worker finishes its batch, then stops trying nonces
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let tried = service.throughput.total();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(service.throughput.total(), tried);
+    }
+
+    #[tokio::test]
+    async fn invalid_work_reports_what_it_achieved() {
+        let (url, _) = serve(test_service(1, cpu_config())).await;
+        let achieved = "08d8374b7c88313fe718e72f5fe257b031e2a0ff221b925f816defe17f852ee5";
+        let validate = |threshold: &str| {
+            json!({ "action": "work_validate", "hash": ROOT, "work": "2bf29ef00786a6bc", "threshold": threshold })
+        };
+        let (status, invalid) = post(&url, validate(EASY_THRESHOLD)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(invalid["valid"], false);
+        assert_eq!(invalid["threshold"], achieved);
+        let multiplier: f64 = invalid["multiplier"].as_str().unwrap().parse().unwrap();
+        assert!((multiplier - 0.0000000154).abs() < 0.0000000001, "{}", multiplier);
+        let (_, valid) = post(&url, validate(achieved)).await;
+        assert_eq!(valid["valid"], true);
+        assert_eq!(valid["threshold"], achieved);
+        assert_eq!(valid["multiplier"], invalid["multiplier"]);
+    }
+
+    #[tokio::test]
+    async fn method_not_allowed_lists_the_allowed_method() {
+        let (url, _) = serve(test_service(1, cpu_config())).await;
+        for (method, path, allow) in [
+            (hyper::Method::GET, "/", "POST"),
+            (hyper::Method::PUT, "/", "POST"),
+            (hyper::Method::POST, "/version", "GET"),
+        ] {
+            let (resp, err) = request(method.clone(), &format!("{}{}", url, path), "").await;
+            assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED, "{} {}", method, path);
+            assert_eq!(resp.headers()[hyper::header::ALLOW], allow, "{} {}", method, path);
+            assert_eq!(err["code"], "METHOD_NOT_ALLOWED");
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnecting_cancels_the_rest_of_a_count() {
+        let service = test_service(1, cpu_config());
+        let (url, _) = serve(service.clone()).await;
+        let threshold = "ffff000000000000000000000000000000000000000000000000000000000000";
+        let request = post_raw(
+            &url,
+            json!({ "action": "work_generate", "hash": ROOT, "threshold": threshold, "count": 64 }),
+        );
+        wait_for(|| service.work_state.0.lock().callback.is_some()).await;
+        let first = service.work_state.0.lock().attempts.clone();
+        // Each work gets a task, and so an attempts counter, of its own
+        wait_for(|| {
+            let state = service.work_state.0.lock();
+            state.callback.is_some() && !Arc::ptr_eq(&state.attempts, &first)
+        })
+        .await;
+        drop(request);
+        wait_for(|| {
+            let state = service.work_state.0.lock();
+            state.callback.is_none() && state.future_work.is_empty()
+        })
+        .await;
+        let last = service.work_state.0.lock().attempts.clone();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let state = service.work_state.0.lock();
+        assert!(state.callback.is_none());
+        assert!(state.future_work.is_empty());
+        assert!(Arc::ptr_eq(&state.attempts, &last), "Another work of the count started");
+    }
+
+    #[tokio::test]
+    async fn idle_workers_are_parked() {
+        let service = test_service(2, cpu_config());
+        let (url, _) = serve(service.clone()).await;
+        wait_for(|| service.work_state.0.lock().parked.len() == 2).await;
+        let tried = service.throughput.total();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(service.throughput.total(), tried);
+        let (_, generated) = post(
+            &url,
+            json!({ "action": "work_generate", "hash": ROOT, "threshold": EASY_THRESHOLD }),
+        )
+        .await;
+        assert!(generated["work"].is_string(), "{}", generated);
+        wait_for(|| service.throughput.total() > tried).await;
+        wait_for(|| service.work_state.0.lock().parked.len() == 2).await;
+        assert!(service.work_state.0.lock().future_work.is_empty());
+    }
+
+    #[test]
+    fn decimal_thresholds_are_nano_difficulties() {
+        let parse = |threshold: Value| RpcService::parse_threshold_json(&json!({ "threshold": threshold }));
+        let expected = parse(json!("ffffffc000000000000000000000000000000000000000000000000000000000")).unwrap();
+        for decimal in [
+            json!(18446743798831644672u64),
+            json!("18446743798831644672"),
+            json!("00018446743798831644672"),
+        ] {
+            assert_eq!(parse(decimal.clone()).unwrap(), expected, "{}", decimal);
+        }
+        assert_eq!(parse(json!(u64::MAX.to_string())).unwrap()[..8], [0xff; 8]);
+        assert_eq!(parse(json!("0")).unwrap(), [0; 32]);
+        // Past u64, neither a difficulty nor a 32 byte hex threshold
+        for overflow in [json!("18446744073709551616"), json!(18446744073709551616.0)] {
+            assert_eq!(parse(overflow.clone()).unwrap_err()["code"], "BAD_THRESHOLD", "{}", overflow);
+        }
+        // 64 digits would overflow, so they are a hex threshold
+        assert_eq!(parse(json!("1".repeat(64))).unwrap(), [0x11; 32]);
+    }
+
+    #[tokio::test]
+    async fn difficulty_names_map_to_their_thresholds() {
+        let mut service = test_service(1, cpu_config());
+        let names = [("send", [0xf0; 32]), ("receive", [0xe0; 32]), ("easy", [0; 32])];
+        service.difficulty_names = Arc::new(names.iter().map(|(name, threshold)| (name.to_string(), *threshold)).collect());
+        for (name, threshold) in names {
+            assert_eq!(service.parse_difficulty_json(&json!({ "difficulty_name": name })).unwrap(), threshold);
+        }
+        // An explicit threshold or multiplier takes precedence
+        let both = json!({ "difficulty_name": "send", "threshold": EASY_THRESHOLD });
+        assert_eq!(hex::encode(service.parse_difficulty_json(&both).unwrap()), EASY_THRESHOLD);
+        let err = service.parse_difficulty_json(&json!({ "difficulty_name": "sned" })).unwrap_err();
+        assert_eq!(err["code"], "BAD_DIFFICULTY_NAME");
+        assert_eq!(err["hint"], "Expecting one of easy, receive, send for difficulty_name");
+        let (url, _) = serve(service).await;
+        let (_, resp) = post(
+            &url,
+            json!({ "action": "work_validate", "hash": ROOT, "work": "2bf29ef00786a6bc", "difficulty_name": "easy" }),
+        )
+        .await;
+        assert_eq!(resp["valid"], true, "{}", resp);
+        let err = test_service(1, cpu_config()).parse_difficulty_json(&json!({ "difficulty_name": "send" })).unwrap_err();
+        assert_eq!(err["hint"], "No difficulty names are configured (see --difficulty-name)");
+    }
+
+    #[test]
+    fn fair_queue_takes_turns_between_clients() {
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let order = |fair_queue: bool| {
+            let work_state = (Mutex::new(WorkState::default()), Condvar::new());
+            {
+                let mut state = work_state.0.lock();
+                state.n_workers = 1;
+                state.fair_queue = fair_queue;
+            }
+            let _results: Vec<_> = [(1, a), (2, a), (3, a), (4, b), (5, b)]
+                .into_iter()
+                .map(|(root, client)| queue_work_from(&work_state, [root; 32], Some(client)))
+                .collect();
+            let mut order = Vec::new();
+            let mut state = work_state.0.lock();
+            while state.callback.is_some() {
+                order.push(state.root[0]);
+                state.solve([0; 8], Worker::Cpu(0), 0, &work_state.1);
+            }
+            order
+        };
+        assert_eq!(order(true), [1, 4, 2, 5, 3]);
+        assert_eq!(order(false), [1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn cancelled_work_says_why() {
+        let mut service = test_service(1, cpu_config());
+        service.work_timeout = Some(Duration::from_millis(500));
+        service.strict_http_status = true;
+        let (url, server) = serve(service.clone()).await;
+        let generate = |root: [u8; 32]| {
+            json!({ "action": "work_generate", "hash": hex::encode(root), "threshold": UNREACHABLE_THRESHOLD })
+        };
+        let idle = || {
+            let state = service.work_state.0.lock();
+            state.callback.is_none() && state.future_work.is_empty()
+        };
+
+        let (status, resp) = post(&url, generate([1; 32])).await;
+        assert_eq!((status, &resp["code"]), (StatusCode::SERVICE_UNAVAILABLE, &json!("WORK_TIMEOUT")), "{}", resp);
+        assert!(idle());
+
+        let pending = tokio::spawn({
+            let url = url.clone();
+            async move { post(&url, generate([2; 32])).await }
+        });
+        wait_for(|| service.work_state.0.lock().callback.is_some()).await;
+        post(&url, json!({ "action": "work_cancel", "hash": hex::encode([2; 32]) })).await;
+        let (status, resp) = pending.await.unwrap();
+        assert_eq!((status, &resp["code"]), (StatusCode::CONFLICT, &json!("CANCELLED")), "{}", resp);
+
+        // Only precomputed work gives way to a request
+        let precomputing = service.precompute_work([3; 32], [0xff; 32]).unwrap();
+        let (_, resp) = post(&url, json!({ "action": "work_generate", "hash": ROOT, "threshold": EASY_THRESHOLD })).await;
+        assert!(resp["work"].is_string(), "{}", resp);
+        assert!(matches!(precomputing.await, Err(WorkError::Superseded)));
+
+        let pending = tokio::spawn({
+            let url = url.clone();
+            async move { post(&url, generate([4; 32])).await }
+        });
+        wait_for(|| service.work_state.0.lock().callback.is_some()).await;
+        service.shutdown.notify_one();
+        let (status, resp) = pending.await.unwrap();
+        assert_eq!((status, &resp["code"]), (StatusCode::SERVICE_UNAVAILABLE, &json!("SHUTTING_DOWN")), "{}", resp);
+        server.await.unwrap().unwrap();
+        assert!(idle());
+    }
+
+    #[test]
+    fn a_task_goes_to_the_fastest_idle_gpu() {
+        let work_state = (Mutex::new(WorkState::default()), Condvar::new());
+        {
+            let mut state = work_state.0.lock();
+            state.n_workers = 4;
+            state.gpu_health = [100, 300, 200]
+                .into_iter()
+                .map(|hashes| {
+                    let health = Arc::new(gpu::GpuHealth::default());
+                    health.throughput().add(hashes);
+                    health.throughput().update(Duration::from_secs(1));
+                    health
+                })
+                .collect();
+            state.parked = [Worker::Gpu(0), Worker::Gpu(2), Worker::Cpu(0)].into_iter().collect();
+        }
+        let runners = |state: &WorkState| -> Vec<Worker> {
+            [Worker::Gpu(0), Worker::Gpu(1), Worker::Gpu(2), Worker::Cpu(0)]
+                .into_iter()
+                .filter(|&worker| state.runs_task(worker))
+                .collect()
+        };
+        // GPU 1 is the fastest, but still busy with its last dispatch
+        let _first = queue_work(&work_state, [1; 32]);
+        assert!(runners(&work_state.0.lock()) == [Worker::Gpu(2)]);
+        {
+            let mut state = work_state.0.lock();
+            state.parked.insert(Worker::Gpu(1));
+            state.solve([0; 8], Worker::Gpu(2), 0, &work_state.1);
+        }
+        let _second = queue_work(&work_state, [2; 32]);
+        assert!(runners(&work_state.0.lock()) == [Worker::Gpu(1)]);
+        {
+            // The others take over once it gives up on the task
+            let mut state = work_state.0.lock();
+            state.unsuccessful_workers += 1;
+            state.fail_if_no_workers(&work_state.1);
+            assert!(state.callback.is_some());
+            state.unsuccessful_workers -= 1;
+            assert_eq!(runners(&state).len(), 4);
+            state.callback = None;
+            state.all_workers = true;
+        }
+        let _third = queue_work(&work_state, [3; 32]);
+        assert_eq!(runners(&work_state.0.lock()).len(), 4);
+    }
 }
@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use hyper::StatusCode;
+
+use parking_lot::Mutex;
+
+#[derive(Default)]
+struct Timings {
+    count: u64,
+    seconds: f64,
+}
+
+/// Request latencies by action and status, rendered in the Prometheus text format for `/metrics`
+/// next to the hashrate, and with `--phase-metrics` the time spent in each phase of work generation
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, u16), Timings>>,
+    phases: Mutex<BTreeMap<&'static str, Timings>>,
+}
+
+impl Metrics {
+    pub fn record(&self, action: &'static str, status: StatusCode, elapsed: Duration) {
+        let mut requests = self.requests.lock();
+        let timings = requests.entry((action, status.as_u16())).or_default();
+        timings.count += 1;
+        timings.seconds += elapsed.as_secs_f64();
+    }
+
+    pub fn record_phase(&self, phase: &'static str, elapsed: Duration) {
+        let mut phases = self.phases.lock();
+        let timings = phases.entry(phase).or_default();
+        timings.count += 1;
+        timings.seconds += elapsed.as_secs_f64();
+    }
+
+    /// Renders the metrics, with the current `hashrate` of all workers and of each GPU
+    pub fn render(&self, hashrate: f64, gpu_hashrates: &[f64]) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP vite_work_server_hashrate Nonces tried per second, averaged over about 10 seconds\n");
+        out.push_str("# TYPE vite_work_server_hashrate gauge\n");
+        let _ = writeln!(out, "vite_work_server_hashrate {}", hashrate);
+        if !gpu_hashrates.is_empty() {
+            out.push_str("# HELP vite_work_server_gpu_hashrate Nonces tried per second by each GPU\n");
+            out.push_str("# TYPE vite_work_server_gpu_hashrate gauge\n");
+        }
+        for (gpu, hashrate) in gpu_hashrates.iter().enumerate() {
+            let _ = writeln!(out, "vite_work_server_gpu_hashrate{{gpu=\"{}\"}} {}", gpu, hashrate);
+        }
+        out.push_str("# HELP vite_work_server_request_duration_seconds Time taken to answer requests\n");
+        out.push_str("# TYPE vite_work_server_request_duration_seconds summary\n");
+        for ((action, status), timings) in self.requests.lock().iter() {
+            let labels = format!("action=\"{}\",status=\"{}\"", action, status);
+            let _ = writeln!(
+                out,
+                "vite_work_server_request_duration_seconds_sum{{{}}} {}",
+                labels, timings.seconds
+            );
+            let _ = writeln!(
+                out,
+                "vite_work_server_request_duration_seconds_count{{{}}} {}",
+                labels, timings.count
+            );
+        }
+        let phases = self.phases.lock();
+        if !phases.is_empty() {
+            out.push_str("# HELP vite_work_server_phase_duration_seconds Time spent in each phase of work generation\n");
+            out.push_str("# TYPE vite_work_server_phase_duration_seconds summary\n");
+        }
+        for (phase, timings) in phases.iter() {
+            let _ = writeln!(
+                out,
+                "vite_work_server_phase_duration_seconds_sum{{phase=\"{}\"}} {}",
+                phase, timings.seconds
+            );
+            let _ = writeln!(
+                out,
+                "vite_work_server_phase_duration_seconds_count{{phase=\"{}\"}} {}",
+                phase, timings.count
+            );
+        }
+        out
+    }
+}
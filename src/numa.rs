@@ -0,0 +1,75 @@
+use std::io;
+
+/// Whether `--numa-node` does anything on this platform
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Parses a sysfs CPU list such as `0-7,16-23`
+#[cfg(target_os = "linux")]
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (start, end): (usize, usize) = match range.split_once('-') {
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            None => {
+                let cpu = range.parse().ok()?;
+                (cpu, cpu)
+            }
+        };
+        cpus.extend(start..=end);
+    }
+    Some(cpus)
+}
+
+/// The CPUs of a NUMA node
+#[cfg(target_os = "linux")]
+pub fn node_cpus(node: usize) -> io::Result<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    let list = std::fs::read_to_string(path)?;
+    match parse_cpu_list(&list) {
+        Some(cpus) if !cpus.is_empty() => Ok(cpus),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("NUMA node {} has no CPUs", node),
+        )),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn node_cpus(_node: usize) -> io::Result<Vec<usize>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Runs the calling thread on `cpus` only, and allocates its memory from `node`
+#[cfg(target_os = "linux")]
+pub fn bind_current_thread(node: usize, cpus: &[usize]) -> io::Result<()> {
+    const MPOL_BIND: libc::c_int = 2;
+    const WORD_BITS: usize = libc::c_ulong::BITS as usize;
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut nodemask: Vec<libc::c_ulong> = vec![0; node / WORD_BITS + 1];
+        nodemask[node / WORD_BITS] |= 1 << (node % WORD_BITS);
+        // The kernel reads one bit less than maxnode
+        let maxnode = (nodemask.len() * WORD_BITS + 1) as libc::c_ulong;
+        let ret = libc::syscall(
+            libc::SYS_set_mempolicy,
+            MPOL_BIND,
+            nodemask.as_ptr(),
+            maxnode,
+        );
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_current_thread(_node: usize, _cpus: &[usize]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
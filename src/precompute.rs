@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::warn;
+
+use parking_lot::Mutex;
+
+use tokio::sync::mpsc;
+
+/// How often the `--precompute-file` is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A root and the threshold to precompute work for
+pub type Root = ([u8; 32], [u8; 32]);
+
+struct Entry {
+    work: [u8; 8],
+    stored_at: Instant,
+    /// When it was stored, on the cache's clock, as keyed in `by_age`
+    stored: u64,
+    /// When it was last stored or read, on the cache's clock, as keyed in `by_use`
+    last_used: u64,
+    /// From `--precompute-file`, rather than a request
+    precomputed: bool,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_root: HashMap<[u8; 32], Entry>,
+    /// Roots by when they were last used, the least recently used first
+    by_use: BTreeMap<u64, [u8; 32]>,
+    /// Roots by when they were stored, so the first ones are the first to expire
+    by_age: BTreeMap<u64, [u8; 32]>,
+    /// How many entries are from `--precompute-file`
+    precomputed: usize,
+    clock: u64,
+}
+
+impl Entries {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, root: &[u8; 32]) -> Option<Entry> {
+        let entry = self.by_root.remove(root)?;
+        self.by_use.remove(&entry.last_used);
+        self.by_age.remove(&entry.stored);
+        if entry.precomputed {
+            self.precomputed -= 1;
+        }
+        Some(entry)
+    }
+
+    /// Drops the work stored more than `ttl` ago, oldest first
+    fn expire(&mut self, ttl: Option<Duration>) {
+        let ttl = match ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        while let Some((_, &root)) = self.by_age.first_key_value() {
+            if self.by_root[&root].stored_at.elapsed() <= ttl {
+                break;
+            }
+            self.remove(&root);
+        }
+    }
+}
+
+/// Generated work by root, either ahead of time or for earlier requests. Holds up to
+/// `capacity` roots, evicting the least recently used, and forgets work older than `ttl`.
+/// Both take O(log n) per entry, so a full cache doesn't slow down storing work.
+/// Only the RPC handlers use it, never the workers, so it can't hold up their results.
+pub struct Cache {
+    capacity: usize,
+    ttl: Option<Duration>,
+    entries: Mutex<Entries>,
+}
+
+impl Cache {
+    pub fn new(capacity: usize, ttl: Option<Duration>) -> Cache {
+        Cache {
+            capacity,
+            ttl,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    fn expired(&self, entry: &Entry) -> bool {
+        self.ttl.is_some_and(|ttl| entry.stored_at.elapsed() > ttl)
+    }
+
+    pub fn insert(&self, root: [u8; 32], work: [u8; 8], precomputed: bool) {
+        let mut entries = self.entries.lock();
+        entries.remove(&root);
+        let clock = entries.tick();
+        entries.by_use.insert(clock, root);
+        entries.by_age.insert(clock, root);
+        if precomputed {
+            entries.precomputed += 1;
+        }
+        entries.by_root.insert(
+            root,
+            Entry {
+                work,
+                stored_at: Instant::now(),
+                stored: clock,
+                last_used: clock,
+                precomputed,
+            },
+        );
+        if entries.by_root.len() > self.capacity {
+            entries.expire(self.ttl);
+        }
+        while entries.by_root.len() > self.capacity {
+            let (_, &oldest) = entries.by_use.first_key_value().expect("Empty cache over capacity");
+            entries.remove(&oldest);
+        }
+    }
+
+    pub fn get(&self, root: [u8; 32]) -> Option<[u8; 8]> {
+        let mut entries = self.entries.lock();
+        if self.expired(entries.by_root.get(&root)?) {
+            entries.remove(&root);
+            return None;
+        }
+        let clock = entries.tick();
+        let entry = entries.by_root.get_mut(&root)?;
+        let last_used = std::mem::replace(&mut entry.last_used, clock);
+        let work = entry.work;
+        entries.by_use.remove(&last_used);
+        entries.by_use.insert(clock, root);
+        Some(work)
+    }
+
+    pub fn remove(&self, root: [u8; 32]) {
+        self.entries.lock().remove(&root);
+    }
+
+    /// How much work is stored, and how much of it is from `--precompute-file`
+    pub fn len(&self) -> (usize, usize) {
+        let mut entries = self.entries.lock();
+        entries.expire(self.ttl);
+        (entries.by_root.len(), entries.precomputed)
+    }
+}
+
+/// Parses a `ROOT [THRESHOLD]` line. Returns `Ok(None)` for blank lines and `#` comments.
+fn parse_line(line: &str, default_threshold: [u8; 32]) -> Result<Option<Root>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let parse = |s: &str, name: &str| -> Result<[u8; 32], String> {
+        hex::decode(s)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| format!("{} {:?} isn't a 32 byte hex string", name, s))
+    };
+    let mut fields = line.split_whitespace();
+    let root = parse(fields.next().unwrap_or_default(), "Root")?;
+    let threshold = match fields.next() {
+        Some(threshold) => parse(threshold, "Threshold")?,
+        None => default_threshold,
+    };
+    if fields.next().is_some() {
+        return Err("Expecting a root and an optional threshold".to_string());
+    }
+    Ok(Some((root, threshold)))
+}
+
+/// Reads roots from `path` (`-` for stdin) and sends each new one once.
+/// A file is read again whenever it is modified, so roots can be appended to it.
+pub fn spawn_feed(
+    path: PathBuf,
+    default_threshold: [u8; 32],
+    sender: mpsc::UnboundedSender<Root>,
+) {
+    thread::spawn(move || {
+        let mut seen = HashSet::new();
+        let mut feed = |source: &str, lines: &mut dyn Iterator<Item = io::Result<String>>| {
+            for (i, line) in lines.enumerate() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => {
+                        warn!("Failed to read precompute roots from {}: {}", source, err);
+                        return;
+                    }
+                };
+                match parse_line(&line, default_threshold) {
+                    Ok(Some((root, threshold))) => {
+                        if seen.insert(root) && sender.send((root, threshold)).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => warn!("Skipping line {} of {}: {}", i + 1, source, err),
+                }
+            }
+        };
+        if path == Path::new("-") {
+            feed("stdin", &mut io::stdin().lock().lines());
+            return;
+        }
+        let source = path.display().to_string();
+        let mut last_modified: Option<SystemTime> = None;
+        loop {
+            match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                Ok(modified) if Some(modified) != last_modified => {
+                    last_modified = Some(modified);
+                    match fs::File::open(&path) {
+                        Ok(file) => feed(&source, &mut io::BufReader::new(file).lines()),
+                        Err(err) => warn!("Failed to open {}: {}", source, err),
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Failed to check {} for changes: {}", source, err),
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let cache = Cache::new(2, None);
+        cache.insert([1; 32], [1; 8], false);
+        cache.insert([2; 32], [2; 8], true);
+        assert_eq!(cache.get([1; 32]), Some([1; 8]));
+        cache.insert([3; 32], [3; 8], false);
+        assert_eq!(cache.get([2; 32]), None);
+        assert_eq!(cache.len(), (2, 0));
+        // Storing a root again makes it the most recently used
+        cache.insert([1; 32], [4; 8], true);
+        cache.insert([5; 32], [5; 8], false);
+        assert_eq!(cache.get([3; 32]), None);
+        assert_eq!(cache.get([1; 32]), Some([4; 8]));
+        assert_eq!(cache.len(), (2, 1));
+        cache.remove([1; 32]);
+        assert_eq!(cache.len(), (1, 0));
+    }
+
+    #[test]
+    fn forgets_expired_work_first() {
+        let cache = Cache::new(2, Some(Duration::from_millis(50)));
+        cache.insert([1; 32], [1; 8], true);
+        thread::sleep(Duration::from_millis(100));
+        cache.insert([2; 32], [2; 8], false);
+        assert_eq!(cache.get([2; 32]), Some([2; 8]));
+        // The expired root goes first, before the least recently used
+        cache.insert([3; 32], [3; 8], false);
+        assert_eq!(cache.len(), (2, 0));
+        assert_eq!(cache.get([1; 32]), None);
+        assert_eq!(cache.get([2; 32]), Some([2; 8]));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get([3; 32]), None);
+        assert_eq!(cache.len(), (0, 0));
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+struct Windows {
+    start: Instant,
+    current: u64,
+    previous: u64,
+}
+
+/// The rate of incoming requests over a sliding one second window, estimated from the
+/// counts of the current and previous fixed windows
+pub struct RequestRate {
+    limit: u64,
+    windows: Mutex<Windows>,
+}
+
+impl RequestRate {
+    pub fn new(limit: u64) -> RequestRate {
+        RequestRate {
+            limit,
+            windows: Mutex::new(Windows {
+                start: Instant::now(),
+                current: 0,
+                previous: 0,
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Counts a request, and returns whether the rate including it is over the limit
+    pub fn hit(&self) -> bool {
+        self.hit_at(Instant::now())
+    }
+
+    fn hit_at(&self, now: Instant) -> bool {
+        let mut windows = self.windows.lock();
+        let elapsed = now - windows.start;
+        if elapsed >= WINDOW * 2 {
+            windows.start = now;
+            windows.previous = 0;
+            windows.current = 0;
+        } else if elapsed >= WINDOW {
+            windows.start += WINDOW;
+            windows.previous = windows.current;
+            windows.current = 0;
+        }
+        windows.current += 1;
+        // The part of the previous window still within the last second
+        let overlap = 1.0 - (now - windows.start).as_secs_f64() / WINDOW.as_secs_f64();
+        let rate = windows.previous as f64 * overlap + windows.current as f64;
+        rate > self.limit as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_requests_over_the_limit() {
+        let rate = RequestRate::new(5);
+        let start = rate.windows.lock().start;
+        for _ in 0..5 {
+            assert!(!rate.hit_at(start));
+        }
+        assert!(rate.hit_at(start));
+        // Rejected requests count too
+        assert!(rate.hit_at(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn allows_requests_again_after_the_window() {
+        let rate = RequestRate::new(5);
+        let start = rate.windows.lock().start;
+        for _ in 0..6 {
+            rate.hit_at(start);
+        }
+        // Just after the window, almost all of the previous one is still within the last second
+        assert!(rate.hit_at(start + WINDOW + Duration::from_millis(1)));
+        // Half way through the next one, half of it is: 6 / 2 + 2 requests
+        assert!(!rate.hit_at(start + WINDOW + WINDOW / 2));
+        // Two windows later, none of it is
+        let later = start + WINDOW * 4;
+        for _ in 0..5 {
+            assert!(!rate.hit_at(later));
+        }
+        assert!(rate.hit_at(later));
+    }
+}
@@ -0,0 +1,101 @@
+const IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const SIGMA: [[usize; 16]; 12] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+];
+
+fn mix(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+fn compress(h: &mut [u64; 8], block: &[u8; 128], bytes: u128, last: bool) {
+    let mut m = [0u64; 16];
+    for (word, chunk) in m.iter_mut().zip(block.chunks_exact(8)) {
+        *word = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    let mut v = [0u64; 16];
+    v[..8].copy_from_slice(h);
+    v[8..].copy_from_slice(&IV);
+    v[12] ^= bytes as u64;
+    v[13] ^= (bytes >> 64) as u64;
+    if last {
+        v[14] = !v[14];
+    }
+    for s in SIGMA.iter() {
+        mix(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+        mix(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+        mix(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+        mix(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+        mix(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+        mix(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+        mix(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+        mix(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+    }
+    for i in 0..8 {
+        h[i] ^= v[i] ^ v[i + 8];
+    }
+}
+
+/// Blake2b of `data` with an `out.len()` byte digest, a key of up to 64 bytes and a
+/// personalization of up to 16 bytes. A plain RFC 7693 implementation sharing no code with
+/// the `blake2` crate or the OpenCL kernel, to double check work with (`--double-check`).
+pub fn blake2b(out: &mut [u8], key: &[u8], personal: &[u8], data: &[u8]) {
+    let mut h = IV;
+    h[0] ^= 0x0101_0000 ^ ((key.len() as u64) << 8) ^ out.len() as u64;
+    let mut personal_block = [0u8; 16];
+    personal_block[..personal.len()].copy_from_slice(personal);
+    h[6] ^= u64::from_le_bytes(personal_block[..8].try_into().unwrap());
+    h[7] ^= u64::from_le_bytes(personal_block[8..].try_into().unwrap());
+
+    let mut input = Vec::with_capacity(128 + data.len());
+    if !key.is_empty() {
+        input.extend_from_slice(key);
+        input.resize(128, 0);
+    }
+    input.extend_from_slice(data);
+    let mut block = [0u8; 128];
+    let mut bytes = 0u128;
+    let mut chunks = input.chunks(128).peekable();
+    if chunks.peek().is_none() {
+        compress(&mut h, &block, 0, true);
+    }
+    while let Some(chunk) = chunks.next() {
+        block = [0u8; 128];
+        block[..chunk.len()].copy_from_slice(chunk);
+        bytes += chunk.len() as u128;
+        compress(&mut h, &block, bytes, chunks.peek().is_none());
+    }
+
+    let mut digest = [0u8; 64];
+    for (chunk, word) in digest.chunks_exact_mut(8).zip(h.iter()) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    out.copy_from_slice(&digest[..out.len()]);
+}
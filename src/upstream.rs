@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+
+use serde_json::{json, Value};
+
+/// Another work server that `work_generate` is forwarded to when the local workers fail
+/// (`--upstream`), or raced against them (`--peer`)
+pub struct Upstream {
+    client: Client<HttpConnector>,
+    uri: Uri,
+    timeout: Duration,
+}
+
+impl Upstream {
+    pub fn new(url: &str, timeout: Duration) -> Result<Upstream, String> {
+        let uri: Uri = url.parse().map_err(|err| format!("{}", err))?;
+        match uri.scheme_str() {
+            Some("http") => {}
+            Some(scheme) => return Err(format!("Unsupported scheme {:?}, only http is", scheme)),
+            None => return Err("Missing http:// scheme".to_string()),
+        }
+        Ok(Upstream {
+            client: Client::new(),
+            uri,
+            timeout,
+        })
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Asks the upstream for work, returned in the order it is hashed. The caller must
+    /// validate it.
+    pub async fn work_generate(&self, root: [u8; 32], threshold: [u8; 32]) -> Result<[u8; 8], String> {
+        let body = json!({
+            "action": "work_generate",
+            "hash": hex::encode_upper(root),
+            "threshold": hex::encode(threshold),
+        });
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .map_err(|err| format!("{}", err))?;
+        let request = async {
+            let resp = self.client.request(req).await?;
+            let status = resp.status();
+            hyper::body::to_bytes(resp.into_body())
+                .await
+                .map(|body| (status, body))
+        };
+        let (status, body) = tokio::time::timeout(self.timeout, request)
+            .await
+            .map_err(|_| format!("No response within {}s", self.timeout.as_secs()))?
+            .map_err(|err| format!("{}", err))?;
+        let resp: Value = serde_json::from_slice(&body)
+            .map_err(|_| format!("Response with status {} isn't JSON", status))?;
+        if let Some(err) = resp.get("error") {
+            return Err(format!(
+                "{} ({})",
+                err.as_str().unwrap_or_default(),
+                resp.get("code").and_then(Value::as_str).unwrap_or("no code")
+            ));
+        }
+        // Work servers answer with big endian work, padded with leading zeros when short
+        let work = resp
+            .get("work")
+            .and_then(Value::as_str)
+            .and_then(|work| hex::decode(work).ok())
+            .filter(|work| !work.is_empty() && work.len() <= 8)
+            .ok_or_else(|| format!("Response with status {} has no valid work", status))?;
+        let mut out = [0u8; 8];
+        for (byte, out) in work.iter().rev().zip(out.iter_mut()) {
+            *out = *byte;
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+
+use log::warn;
+
+use serde_json::Value;
+
+/// How many times a notification is sent before giving up on it
+const ATTEMPTS: u32 = 3;
+
+/// How long each attempt may take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An http:// URL that each generated work is POSTed to (`--webhook`)
+pub struct Webhook {
+    client: Client<HttpConnector>,
+    uri: Uri,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Result<Webhook, String> {
+        let uri: Uri = url.parse().map_err(|err| format!("{}", err))?;
+        match uri.scheme_str() {
+            Some("http") => {}
+            Some(scheme) => return Err(format!("Unsupported scheme {:?}, only http is", scheme)),
+            None => return Err("Missing http:// scheme".to_string()),
+        }
+        Ok(Webhook {
+            client: Client::new(),
+            uri,
+        })
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Sends `body` in the background, retrying a few times with a growing delay. Failures
+    /// are only logged.
+    pub fn notify(self: &Arc<Self>, body: Value) {
+        let webhook = self.clone();
+        tokio::spawn(async move {
+            let body = body.to_string();
+            let mut delay = Duration::from_secs(1);
+            for attempt in 1..=ATTEMPTS {
+                let err = match webhook.send(body.clone()).await {
+                    Ok(()) => return,
+                    Err(err) => err,
+                };
+                if attempt == ATTEMPTS {
+                    warn!("Failed to notify webhook {}, giving up: {}", webhook.uri, err);
+                    return;
+                }
+                warn!("Failed to notify webhook {}, retrying in {}s: {}", webhook.uri, delay.as_secs(), err);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        });
+    }
+
+    async fn send(&self, body: String) -> Result<(), String> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(|err| format!("{}", err))?;
+        let resp = tokio::time::timeout(TIMEOUT, self.client.request(req))
+            .await
+            .map_err(|_| format!("No response within {}s", TIMEOUT.as_secs()))?
+            .map_err(|err| format!("{}", err))?;
+        if !resp.status().is_success() {
+            return Err(format!("Status {}", resp.status()));
+        }
+        Ok(())
+    }
+}
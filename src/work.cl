@@ -190,6 +190,26 @@ static inline int blake2b_init(blake2b_state *S, const uchar outlen)
     return blake2b_init_param(S, P);
 }
 
+/* init for the work hash, using the key and personalization passed in at build time */
+static inline int blake2b_init_work(blake2b_state *S)
+{
+    blake2b_param P[1];
+
+    P->digest_length = WORK_HASH_LENGTH;
+    P->key_length = WORK_KEY_LENGTH;
+    P->fanout = 1;
+    P->depth = 1;
+    store32(&P->leaf_length, 0);
+    store64(&P->node_offset, 0);
+    P->node_depth = 0;
+    P->inner_length = 0;
+    ucharset(P->reserved, 0, sizeof(P->reserved));
+    ucharset(P->salt, 0, sizeof(P->salt));
+    for (int i = 0; i < BLAKE2B_PERSONALBYTES; ++i)
+        P->personal[i] = work_personal[i];
+    return blake2b_init_param(S, P);
+}
+
 static int blake2b_compress(blake2b_state *S, __private const uchar block[BLAKE2B_BLOCKBYTES])
 {
     ulong m[16];
@@ -359,10 +379,16 @@ __kernel void work(__global ulong *nonce, __global uchar *data, __global uchar *
     ucharcpyglb(temp_data, data, 32);
 
     blake2b_state state;
-    blake2b_init(&state, 32);
+    blake2b_init_work(&state);
+    if (WORK_KEY_LENGTH > 0) {
+        uchar key_block[BLAKE2B_BLOCKBYTES] = {0};
+        for (int i = 0; i < WORK_KEY_LENGTH; ++i)
+            key_block[i] = work_key[i];
+        blake2b_update(&state, key_block, BLAKE2B_BLOCKBYTES);
+    }
     blake2b_update(&state, (uchar *)&new_nonce, 8);
     blake2b_update(&state, temp_data, 32);
-    blake2b_final(&state, temp_result, 32);
+    blake2b_final(&state, temp_result, WORK_HASH_LENGTH);
 
     if (quick32bytesGreater(temp_result, target)) {
         *result = new_nonce;